@@ -0,0 +1,252 @@
+/*
+* Copyright 2023-2024 Juan Miguel Giraldo
+*
+* Licensed under the Apache License, Version 2.0 (the "License");
+* you may not use this file except in compliance with the License.
+* You may obtain a copy of the License at
+*
+*     http://www.apache.org/licenses/LICENSE-2.0
+*
+* Unless required by applicable law or agreed to in writing, software
+* distributed under the License is distributed on an "AS IS" BASIS,
+* WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+* See the License for the specific language governing permissions and
+* limitations under the License.
+*/
+
+//! ACPI table discovery: locates the RSDP, walks the RSDT/XSDT, and parses
+//! the MADT into the Local APIC IDs, IO APIC base, and interrupt source
+//! overrides that `apic` needs to move VEKOS off the legacy PICs. This is
+//! the prerequisite for `Scheduler` ever being made multi-core aware.
+//!
+//! Every address touched here - the EBDA pointer, the RSDP, the RSDT/XSDT
+//! and whatever tables it points at - is a *physical* address, and VEKOS
+//! is not identity-mapped. [`set_phys_mem_offset`] must be called once
+//! paging is up and before [`init`], mirroring
+//! `virtio_blk::set_phys_mem_offset`.
+
+use alloc::vec::Vec;
+use spin::Mutex;
+
+#[derive(Debug)]
+pub enum AcpiError {
+    RsdpNotFound,
+    ChecksumMismatch,
+    MadtNotFound,
+    PhysMemOffsetNotSet,
+}
+
+/// Offset of the direct physical memory map, the same value `kernel_main`
+/// passes to `MemoryManager::new`.
+static PHYS_MEM_OFFSET: Mutex<Option<u64>> = Mutex::new(None);
+
+/// Records the physical memory offset so [`init`] can translate the
+/// physical table addresses it reads from firmware structures. Must be
+/// called before `init()`, once paging is up.
+pub fn set_phys_mem_offset(offset: u64) {
+    *PHYS_MEM_OFFSET.lock() = Some(offset);
+}
+
+fn phys_to_virt(offset: u64, phys: usize) -> usize {
+    phys + offset as usize
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptSourceOverride {
+    pub bus_source: u8,
+    pub irq_source: u8,
+    pub global_system_interrupt: u32,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct CpuTopology {
+    pub local_apic_ids: Vec<u8>,
+    pub io_apic_base: Option<u32>,
+    pub io_apic_gsi_base: u32,
+    pub overrides: Vec<InterruptSourceOverride>,
+}
+
+impl CpuTopology {
+    pub fn cpu_count(&self) -> usize {
+        self.local_apic_ids.len()
+    }
+}
+
+#[repr(C, packed)]
+struct RsdpV1 {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+}
+
+#[repr(C, packed)]
+struct RsdpV2 {
+    v1: RsdpV1,
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+#[repr(C, packed)]
+struct SdtHeader {
+    signature: [u8; 4],
+    length: u32,
+    revision: u8,
+    checksum: u8,
+    oem_id: [u8; 6],
+    oem_table_id: [u8; 8],
+    oem_revision: u32,
+    creator_id: u32,
+    creator_revision: u32,
+}
+
+fn sum_bytes(ptr: *const u8, len: usize) -> u8 {
+    let mut sum: u8 = 0;
+    for i in 0..len {
+        sum = sum.wrapping_add(unsafe { core::ptr::read_volatile(ptr.add(i)) });
+    }
+    sum
+}
+
+/// Scans the EBDA and `0xE0000-0xFFFFF` for the 8-byte `"RSD PTR "`
+/// signature, checksumming each candidate before accepting it. `addr`
+/// ranges scanned here are physical; every read goes through
+/// [`phys_to_virt`].
+fn find_rsdp(offset: u64) -> Option<usize> {
+    let ebda_segment_ptr = phys_to_virt(offset, 0x40e) as *const u16;
+    let ebda_base = (unsafe { core::ptr::read_volatile(ebda_segment_ptr) } as usize) << 4;
+
+    let ranges: [(usize, usize); 2] = [(ebda_base, ebda_base + 1024), (0xE0000, 0x100000)];
+
+    for (start, end) in ranges {
+        let mut addr = start;
+        while addr < end {
+            let candidate = phys_to_virt(offset, addr) as *const [u8; 8];
+            let signature = unsafe { core::ptr::read_volatile(candidate) };
+            if &signature == b"RSD PTR " {
+                let checksum = sum_bytes(phys_to_virt(offset, addr) as *const u8, core::mem::size_of::<RsdpV1>());
+                if checksum == 0 {
+                    return Some(addr);
+                }
+            }
+            addr += 16;
+        }
+    }
+    None
+}
+
+fn header_at(offset: u64, addr: usize) -> &'static SdtHeader {
+    unsafe { &*(phys_to_virt(offset, addr) as *const SdtHeader) }
+}
+
+fn entry_pointers(offset: u64, rsdt_addr: usize, is_xsdt: bool) -> Vec<usize> {
+    let header = header_at(offset, rsdt_addr);
+    let header_len = header.length as usize;
+    let entries_start = rsdt_addr + core::mem::size_of::<SdtHeader>();
+    let entries_bytes = header_len - core::mem::size_of::<SdtHeader>();
+
+    let mut pointers = Vec::new();
+    if is_xsdt {
+        let count = entries_bytes / 8;
+        for i in 0..count {
+            let ptr = phys_to_virt(offset, entries_start + i * 8) as *const u64;
+            pointers.push(unsafe { core::ptr::read_unaligned(ptr) } as usize);
+        }
+    } else {
+        let count = entries_bytes / 4;
+        for i in 0..count {
+            let ptr = phys_to_virt(offset, entries_start + i * 4) as *const u32;
+            pointers.push(unsafe { core::ptr::read_unaligned(ptr) } as usize);
+        }
+    }
+    pointers
+}
+
+const MADT_ENTRY_LOCAL_APIC: u8 = 0;
+const MADT_ENTRY_IO_APIC: u8 = 1;
+const MADT_ENTRY_INTERRUPT_OVERRIDE: u8 = 2;
+
+fn parse_madt(offset: u64, madt_addr: usize) -> CpuTopology {
+    let header = header_at(offset, madt_addr);
+    let length = header.length as usize;
+
+    let mut topology = CpuTopology::default();
+    let mut entry_addr = madt_addr + core::mem::size_of::<SdtHeader>() + 8;
+    let end = madt_addr + length;
+
+    while entry_addr + 2 <= end {
+        let entry_type = unsafe { core::ptr::read_volatile(phys_to_virt(offset, entry_addr) as *const u8) };
+        let entry_len = unsafe { core::ptr::read_volatile(phys_to_virt(offset, entry_addr + 1) as *const u8) } as usize;
+        if entry_len < 2 {
+            break;
+        }
+
+        match entry_type {
+            MADT_ENTRY_LOCAL_APIC => {
+                let flags = unsafe { core::ptr::read_volatile(phys_to_virt(offset, entry_addr + 4) as *const u32) };
+                if flags & 1 != 0 {
+                    let apic_id = unsafe { core::ptr::read_volatile(phys_to_virt(offset, entry_addr + 3) as *const u8) };
+                    topology.local_apic_ids.push(apic_id);
+                }
+            }
+            MADT_ENTRY_IO_APIC => {
+                let io_apic_addr = unsafe { core::ptr::read_unaligned(phys_to_virt(offset, entry_addr + 4) as *const u32) };
+                let gsi_base = unsafe { core::ptr::read_unaligned(phys_to_virt(offset, entry_addr + 8) as *const u32) };
+                topology.io_apic_base = Some(io_apic_addr);
+                topology.io_apic_gsi_base = gsi_base;
+            }
+            MADT_ENTRY_INTERRUPT_OVERRIDE => {
+                let bus_source = unsafe { core::ptr::read_volatile(phys_to_virt(offset, entry_addr + 2) as *const u8) };
+                let irq_source = unsafe { core::ptr::read_volatile(phys_to_virt(offset, entry_addr + 3) as *const u8) };
+                let gsi = unsafe { core::ptr::read_unaligned(phys_to_virt(offset, entry_addr + 4) as *const u32) };
+                topology.overrides.push(InterruptSourceOverride {
+                    bus_source,
+                    irq_source,
+                    global_system_interrupt: gsi,
+                });
+            }
+            _ => {}
+        }
+
+        entry_addr += entry_len;
+    }
+
+    topology
+}
+
+/// Locates the RSDP, walks the RSDT/XSDT looking for the MADT, and returns
+/// the discovered CPU topology (Local APIC IDs, IO APIC base, interrupt
+/// source overrides). Called after `gdt::init()` and `interrupts::init_idt()`
+/// (so a stray fault while walking firmware tables has a handler to land
+/// in), once [`set_phys_mem_offset`] has been given the direct physical
+/// map offset, and before interrupts switch from the legacy PICs to the
+/// APIC.
+pub fn init() -> Result<CpuTopology, AcpiError> {
+    let offset = PHYS_MEM_OFFSET.lock().ok_or(AcpiError::PhysMemOffsetNotSet)?;
+
+    let rsdp_addr = find_rsdp(offset).ok_or(AcpiError::RsdpNotFound)?;
+    let rsdp_v1 = unsafe { &*(phys_to_virt(offset, rsdp_addr) as *const RsdpV1) };
+
+    let (root_addr, is_xsdt) = if rsdp_v1.revision >= 2 {
+        let rsdp_v2 = unsafe { &*(phys_to_virt(offset, rsdp_addr) as *const RsdpV2) };
+        let checksum = sum_bytes(phys_to_virt(offset, rsdp_addr) as *const u8, core::mem::size_of::<RsdpV2>());
+        if checksum != 0 {
+            return Err(AcpiError::ChecksumMismatch);
+        }
+        (rsdp_v2.xsdt_address as usize, true)
+    } else {
+        (rsdp_v1.rsdt_address as usize, false)
+    };
+
+    for table_addr in entry_pointers(offset, root_addr, is_xsdt) {
+        let header = header_at(offset, table_addr);
+        if &header.signature == b"APIC" {
+            return Ok(parse_madt(offset, table_addr));
+        }
+    }
+
+    Err(AcpiError::MadtNotFound)
+}
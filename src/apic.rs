@@ -0,0 +1,176 @@
+/*
+* Copyright 2023-2024 Juan Miguel Giraldo
+*
+* Licensed under the Apache License, Version 2.0 (the "License");
+* you may not use this file except in compliance with the License.
+* You may obtain a copy of the License at
+*
+*     http://www.apache.org/licenses/LICENSE-2.0
+*
+* Unless required by applicable law or agreed to in writing, software
+* distributed under the License is distributed on an "AS IS" BASIS,
+* WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+* See the License for the specific language governing permissions and
+* limitations under the License.
+*/
+
+//! Local APIC / IO APIC bring-up, using the topology `acpi` discovered to
+//! move VEKOS off the legacy 8259 PICs. Masks both PICs, enables the Local
+//! APIC via the spurious-interrupt vector register, and programs IO APIC
+//! redirection entries for the keyboard and timer so they land on the same
+//! IDT vectors the PIC path used to route them to.
+//!
+//! The Local APIC base (from `IA32_APIC_BASE`) and the IO APIC base (from
+//! the MADT) are both physical MMIO addresses, and VEKOS is not
+//! identity-mapped. [`set_phys_mem_offset`] must be called once paging is
+//! up and before [`init`], mirroring `virtio_blk::set_phys_mem_offset`.
+
+use crate::acpi::CpuTopology;
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+const PIC1_DATA: u16 = 0x21;
+const PIC2_DATA: u16 = 0xA1;
+
+/// Offset of the direct physical memory map, the same value `kernel_main`
+/// passes to `MemoryManager::new`.
+static PHYS_MEM_OFFSET: Mutex<Option<u64>> = Mutex::new(None);
+
+/// Records the physical memory offset so the Local/IO APIC accessors can
+/// translate the MMIO addresses they're given. Must be called before
+/// [`init`], once paging is up.
+pub fn set_phys_mem_offset(offset: u64) {
+    *PHYS_MEM_OFFSET.lock() = Some(offset);
+}
+
+fn phys_to_virt(phys: usize) -> usize {
+    let offset = PHYS_MEM_OFFSET
+        .lock()
+        .expect("apic::set_phys_mem_offset must be called before touching APIC MMIO");
+    phys + offset as usize
+}
+
+const APIC_SPURIOUS_VECTOR_REGISTER: usize = 0xf0;
+const APIC_SOFTWARE_ENABLE: u32 = 1 << 8;
+
+/// Spurious-interrupt vector, written into the low byte of the
+/// spurious-interrupt vector register.
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+/// Timer and keyboard vectors start where the PIC's vector window used to
+/// start, so their IDT entries don't need to move.
+pub const TIMER_VECTOR: u8 = 0x20;
+pub const KEYBOARD_VECTOR: u8 = 0x21;
+
+const IOAPIC_REGSEL: usize = 0x00;
+const IOAPIC_IOWIN: usize = 0x10;
+const IOAPIC_REDTBL_BASE: u32 = 0x10;
+
+/// Masks both legacy PICs so no more IRQs arrive through them. Safe to
+/// call even if the APIC path fails to come up afterwards, since a masked
+/// PIC just means no legacy interrupts at all rather than a double path.
+pub fn mask_legacy_pics() {
+    unsafe {
+        Port::<u8>::new(PIC1_DATA).write(0xff);
+        Port::<u8>::new(PIC2_DATA).write(0xff);
+    }
+}
+
+unsafe fn read_local_apic(base: u64, reg: usize) -> u32 {
+    core::ptr::read_volatile(phys_to_virt(base as usize + reg) as *const u32)
+}
+
+/// Shared with `interrupts::end_of_interrupt`, which writes the EOI
+/// register (`0xb0`) through the same Local APIC base once the APIC path
+/// is live.
+pub(crate) unsafe fn write_local_apic(base: u64, reg: usize, value: u32) {
+    core::ptr::write_volatile(phys_to_virt(base as usize + reg) as *mut u32, value);
+}
+
+/// Reads the Local APIC base from the `IA32_APIC_BASE` MSR (bits 12-35).
+pub(crate) fn local_apic_base() -> u64 {
+    let value = unsafe { x86_64::registers::model_specific::Msr::new(0x1b).read() };
+    value & 0xf_ffff_f000
+}
+
+/// Enables the Local APIC by setting the software-enable bit in the
+/// spurious-interrupt vector register.
+pub fn enable_local_apic() {
+    let base = local_apic_base();
+    unsafe {
+        let value = read_local_apic(base, APIC_SPURIOUS_VECTOR_REGISTER);
+        write_local_apic(
+            base,
+            APIC_SPURIOUS_VECTOR_REGISTER,
+            value | APIC_SOFTWARE_ENABLE | SPURIOUS_VECTOR as u32,
+        );
+    }
+}
+
+unsafe fn write_io_apic(base: u32, reg: u32, value: u32) {
+    core::ptr::write_volatile(phys_to_virt(base as usize) as *mut u32, reg);
+    core::ptr::write_volatile(phys_to_virt(base as usize + IOAPIC_IOWIN) as *mut u32, value);
+}
+
+/// Programs a redirection entry for `gsi`, routing it to `vector` on the
+/// boot-strap Local APIC (destination mode: physical, delivery mode: fixed).
+fn redirect(io_apic_base: u32, gsi: u32, vector: u8, bsp_apic_id: u8) {
+    let low = vector as u32;
+    let high = (bsp_apic_id as u32) << 24;
+    let reg = IOAPIC_REDTBL_BASE + gsi * 2;
+    unsafe {
+        write_io_apic(io_apic_base, reg + 1, high);
+        write_io_apic(io_apic_base, reg, low);
+    }
+}
+
+fn gsi_for_legacy_irq(topology: &CpuTopology, irq: u8) -> u32 {
+    for ov in &topology.overrides {
+        if ov.irq_source == irq {
+            return ov.global_system_interrupt;
+        }
+    }
+    irq as u32
+}
+
+const APIC_LVT_TIMER_REGISTER: usize = 0x320;
+const APIC_TIMER_DIVIDE_CONFIG_REGISTER: usize = 0x3e0;
+const APIC_TIMER_INITIAL_COUNT_REGISTER: usize = 0x380;
+const APIC_LVT_TIMER_PERIODIC: u32 = 1 << 17;
+const APIC_TIMER_DIVIDE_BY_16: u32 = 0x3;
+
+/// Arms the APIC timer in periodic mode on [`TIMER_VECTOR`], replacing the
+/// PIT as the scheduler tick source. `initial_count` comes from the caller
+/// (`time::init`), which passes a fixed value that is not calibrated
+/// against a reference clock.
+pub fn arm_timer(initial_count: u32) {
+    let base = local_apic_base();
+    unsafe {
+        write_local_apic(base, APIC_TIMER_DIVIDE_CONFIG_REGISTER, APIC_TIMER_DIVIDE_BY_16);
+        write_local_apic(
+            base,
+            APIC_LVT_TIMER_REGISTER,
+            APIC_LVT_TIMER_PERIODIC | TIMER_VECTOR as u32,
+        );
+        write_local_apic(base, APIC_TIMER_INITIAL_COUNT_REGISTER, initial_count);
+    }
+}
+
+/// Masks the legacy PICs, enables the Local APIC, and redirects the
+/// keyboard (legacy IRQ1) and timer (legacy IRQ0) through the IO APIC onto
+/// the same vectors `interrupts::init_idt()` already wires up. Flips
+/// `interrupts` over to sending Local APIC EOIs so the redirected IRQs
+/// keep being acknowledged. The APIC timer itself still needs to be armed
+/// by `time::init` via [`arm_timer`] to replace the PIT tick.
+pub fn init(topology: &CpuTopology) {
+    mask_legacy_pics();
+    enable_local_apic();
+    crate::interrupts::set_using_apic(true);
+
+    if let Some(io_apic_base) = topology.io_apic_base {
+        let bsp_apic_id = topology.local_apic_ids.first().copied().unwrap_or(0);
+        let timer_gsi = gsi_for_legacy_irq(topology, 0);
+        let keyboard_gsi = gsi_for_legacy_irq(topology, 1);
+        redirect(io_apic_base, timer_gsi, TIMER_VECTOR, bsp_apic_id);
+        redirect(io_apic_base, keyboard_gsi, KEYBOARD_VECTOR, bsp_apic_id);
+    }
+}
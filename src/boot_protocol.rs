@@ -0,0 +1,172 @@
+/*
+* Copyright 2023-2024 Juan Miguel Giraldo
+*
+* Licensed under the Apache License, Version 2.0 (the "License");
+* you may not use this file except in compliance with the License.
+* You may obtain a copy of the License at
+*
+*     http://www.apache.org/licenses/LICENSE-2.0
+*
+* Unless required by applicable law or agreed to in writing, software
+* distributed under the License is distributed on an "AS IS" BASIS,
+* WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+* See the License for the specific language governing permissions and
+* limitations under the License.
+*/
+
+//! Limine boot protocol support.
+//!
+//! VEKOS normally boots via the `bootloader` crate, which hands us a fixed
+//! `BootInfo` with no framebuffer description of its own, so `kernel_main`
+//! has historically hardcoded the QEMU `-vga std` mode (800x600x32 at
+//! `0xfd000000`). Limine places requests in a `.requests` link section that
+//! the bootloader scans independently of how the kernel is entered, fills in
+//! the matching response, and leaves everything else (heap, paging, the
+//! `bootloader` crate path) untouched. `discover_framebuffer` is the only
+//! entry point `kernel_main` needs: it returns `None` when no Limine
+//! response was ever populated (i.e. we were booted by `bootloader` as
+//! before), in which case the caller keeps using its existing defaults.
+
+const LIMINE_COMMON_MAGIC: [u64; 2] = [0xc7b1dd30df4c8b88, 0x0a82e883a194f07b];
+
+#[repr(C)]
+struct FramebufferRequest {
+    id: [u64; 4],
+    revision: u64,
+    response: *const FramebufferResponse,
+}
+
+unsafe impl Sync for FramebufferRequest {}
+
+#[repr(C)]
+struct HhdmRequest {
+    id: [u64; 4],
+    revision: u64,
+    response: *const HhdmResponse,
+}
+
+unsafe impl Sync for HhdmRequest {}
+
+#[repr(C)]
+struct HhdmResponse {
+    revision: u64,
+    offset: u64,
+}
+
+#[used]
+#[link_section = ".requests"]
+static HHDM_REQUEST: HhdmRequest = HhdmRequest {
+    id: [
+        LIMINE_COMMON_MAGIC[0],
+        LIMINE_COMMON_MAGIC[1],
+        0x48dcf1cb8ad2b852,
+        0x63984e959a98244b,
+    ],
+    revision: 0,
+    response: core::ptr::null(),
+};
+
+/// `RawFramebuffer::address` is the HHDM-mapped kernel-virtual pointer, not
+/// a physical address (Limine boot protocol spec, framebuffer feature).
+/// Reads back through the HHDM request to recover the real physical base.
+fn hhdm_offset() -> u64 {
+    // Volatile: Limine fills this in before entry, so the null initializer
+    // must not be constant-folded into the load.
+    let response = unsafe { core::ptr::read_volatile(&HHDM_REQUEST.response) };
+    if response.is_null() {
+        return 0;
+    }
+    unsafe { (*response).offset }
+}
+
+#[repr(C)]
+struct FramebufferResponse {
+    revision: u64,
+    framebuffer_count: u64,
+    framebuffers: *const *const RawFramebuffer,
+}
+
+#[repr(C)]
+struct RawFramebuffer {
+    address: *mut u8,
+    width: u64,
+    height: u64,
+    pitch: u64,
+    bpp: u16,
+    memory_model: u8,
+    red_mask_size: u8,
+    red_mask_shift: u8,
+    green_mask_size: u8,
+    green_mask_shift: u8,
+    blue_mask_size: u8,
+    blue_mask_shift: u8,
+    _unused: [u8; 7],
+    edid_size: u64,
+    edid: *mut u8,
+    mode_count: u64,
+    modes: *const *const RawFramebuffer,
+}
+
+#[used]
+#[link_section = ".requests"]
+static FRAMEBUFFER_REQUEST: FramebufferRequest = FramebufferRequest {
+    id: [
+        LIMINE_COMMON_MAGIC[0],
+        LIMINE_COMMON_MAGIC[1],
+        0x9d5827dcd881dd75,
+        0xa3148604f6fab11b,
+    ],
+    revision: 0,
+    response: core::ptr::null(),
+};
+
+/// Framebuffer geometry handed to us by the bootloader, independent of
+/// which `framebuffer`/`graphics_hal` struct it ultimately feeds.
+pub struct BootFramebuffer {
+    pub physical_address: u64,
+    pub width: u32,
+    pub height: u32,
+    pub pitch: u32,
+    pub bpp: u8,
+    pub red_mask_size: u8,
+    pub red_mask_pos: u8,
+    pub green_mask_size: u8,
+    pub green_mask_pos: u8,
+    pub blue_mask_size: u8,
+    pub blue_mask_pos: u8,
+}
+
+/// Returns the first framebuffer Limine reported, or `None` if we weren't
+/// booted by a Limine-compatible loader (the response pointer is left null).
+pub fn discover_framebuffer() -> Option<BootFramebuffer> {
+    let response = unsafe { core::ptr::read_volatile(&FRAMEBUFFER_REQUEST.response) };
+    if response.is_null() {
+        return None;
+    }
+
+    let response = unsafe { &*response };
+    if response.framebuffer_count == 0 {
+        return None;
+    }
+
+    let first = unsafe { *response.framebuffers };
+    if first.is_null() {
+        return None;
+    }
+    let fb = unsafe { &*first };
+    let physical_address = (fb.address as u64).saturating_sub(hhdm_offset());
+
+    Some(BootFramebuffer {
+        physical_address,
+        width: fb.width as u32,
+        height: fb.height as u32,
+        pitch: fb.pitch as u32,
+        bpp: fb.bpp as u8,
+        red_mask_size: fb.red_mask_size,
+        red_mask_pos: fb.red_mask_shift,
+        green_mask_size: fb.green_mask_size,
+        green_mask_pos: fb.green_mask_shift,
+        blue_mask_size: fb.blue_mask_size,
+        blue_mask_pos: fb.blue_mask_shift,
+    })
+}
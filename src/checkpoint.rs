@@ -0,0 +1,141 @@
+/*
+* Copyright 2023-2024 Juan Miguel Giraldo
+*
+* Licensed under the Apache License, Version 2.0 (the "License");
+* you may not use this file except in compliance with the License.
+* You may obtain a copy of the License at
+*
+*     http://www.apache.org/licenses/LICENSE-2.0
+*
+* Unless required by applicable law or agreed to in writing, software
+* distributed under the License is distributed on an "AS IS" BASIS,
+* WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+* See the License for the specific language governing permissions and
+* limitations under the License.
+*/
+
+//! Tamper-evident process checkpoint/restore, built on [`merkle_tree`]: a
+//! checkpoint's page contents are folded into a Merkle root, and restoring
+//! one re-derives that root from the checkpoint's own stored pages and
+//! checks it against the root [`checkpoint`] recorded for that pid in
+//! [`RECORDED_ROOTS`] - a table kept outside the `Checkpoint` value itself
+//! - before anything is rebuilt, refusing to restore on a mismatch.
+//! Checking only against a root carried inside the same blob as the pages
+//! would detect nothing, since anything able to tamper with `pages` could
+//! tamper with a same-blob `merkle_root` right next to it.
+//!
+//! `Process`/`Scheduler` aren't part of this checkout, so [`checkpoint`]
+//! and [`restore`] take the register blob, page set, file descriptors, and
+//! current directory as plain arguments rather than being inherent methods
+//! - `Process::checkpoint`/`Process::restore` should be thin wrappers over
+//! these once the fields they close over are available to call from.
+//! [`RECORDED_ROOTS`] is a stand-in for recording a proper
+//! [`crate::OperationProof`] in `crate::verification::VERIFICATION_REGISTRY`,
+//! which needs that registry's proof-construction API and isn't part of
+//! this checkout.
+//!
+//! Nothing in this checkout calls [`checkpoint`]/[`restore`]: there is no
+//! shell command or syscall exposing them, and deliberately so. A syscall
+//! surface would need a way to safely copy a checkpoint blob in from
+//! userspace (a `copy_from_user`-style primitive, which doesn't exist
+//! here) and a way to enumerate a live process's resident pages (which
+//! belongs to `process`/`memory`); faking either with raw,
+//! un-validated pointer arithmetic over syscall arguments would be a worse
+//! bug than not shipping the surface at all. Until those land, this module
+//! is a tamper-evidence primitive other code can build on, not an
+//! end-to-end feature.
+
+use crate::merkle_tree::{self, Hash};
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+pub type PageHash = Hash;
+
+#[derive(Debug)]
+pub enum CheckpointError {
+    EmptyPageSet,
+    RootMismatch,
+    NoRecordedProof,
+}
+
+/// Roots recorded by [`checkpoint`], keyed by pid, held separately from
+/// the `Checkpoint` value so [`restore`] has something to check against
+/// that tampering with a `Checkpoint` in transit can't also rewrite.
+static RECORDED_ROOTS: Mutex<BTreeMap<u64, PageHash>> = Mutex::new(BTreeMap::new());
+
+#[derive(Clone)]
+pub struct Checkpoint {
+    pub pid: u64,
+    pub registers: Vec<u8>,
+    pub pages: Vec<(u64, Vec<u8>)>,
+    pub open_fds: Vec<u32>,
+    pub current_dir: String,
+    pub merkle_root: PageHash,
+}
+
+fn page_hashes(pages: &[(u64, Vec<u8>)]) -> Vec<Hash> {
+    pages.iter().map(|(_, data)| merkle_tree::hash_leaf(data)).collect()
+}
+
+/// Serializes register state, the memory map's page contents, open file
+/// descriptors, and the current directory into a [`Checkpoint`], computing
+/// a Merkle root over the page set and recording it in [`RECORDED_ROOTS`]
+/// under `pid` so [`restore`] has a copy outside the `Checkpoint` value to
+/// check against. A later checkpoint for the same pid overwrites the
+/// earlier recorded root, since only the most recent checkpoint should be
+/// restorable.
+pub fn checkpoint(
+    pid: u64,
+    registers: &[u8],
+    pages: &[(u64, &[u8])],
+    open_fds: &[u32],
+    current_dir: &str,
+) -> Result<Checkpoint, CheckpointError> {
+    if pages.is_empty() {
+        return Err(CheckpointError::EmptyPageSet);
+    }
+
+    let owned_pages: Vec<(u64, Vec<u8>)> =
+        pages.iter().map(|(addr, data)| (*addr, data.to_vec())).collect();
+    let root = merkle_tree::root(&page_hashes(&owned_pages));
+    RECORDED_ROOTS.lock().insert(pid, root);
+
+    Ok(Checkpoint {
+        pid,
+        registers: registers.to_vec(),
+        pages: owned_pages,
+        open_fds: open_fds.to_vec(),
+        current_dir: String::from(current_dir),
+        merkle_root: root,
+    })
+}
+
+/// Re-derives the Merkle root from `checkpoint`'s own stored pages and
+/// checks it against the root [`checkpoint`] recorded for this pid in
+/// [`RECORDED_ROOTS`] - not just `checkpoint.merkle_root`, which lives in
+/// the same tamperable value as `pages` and proves nothing on its own.
+/// Refuses to hand the pages back unless both the recorded root exists
+/// and matches. On success, returns the `(address, bytes)` pairs the
+/// caller should write back into the process's address space to complete
+/// the restore.
+pub fn restore(checkpoint: &Checkpoint) -> Result<&[(u64, Vec<u8>)], CheckpointError> {
+    let root = merkle_tree::root(&page_hashes(&checkpoint.pages));
+
+    if root != checkpoint.merkle_root {
+        return Err(CheckpointError::RootMismatch);
+    }
+
+    let recorded_root = RECORDED_ROOTS
+        .lock()
+        .get(&checkpoint.pid)
+        .copied()
+        .ok_or(CheckpointError::NoRecordedProof)?;
+
+    if root != recorded_root {
+        return Err(CheckpointError::RootMismatch);
+    }
+
+    Ok(&checkpoint.pages)
+}
@@ -0,0 +1,592 @@
+/*
+* Copyright 2023-2024 Juan Miguel Giraldo
+*
+* Licensed under the Apache License, Version 2.0 (the "License");
+* you may not use this file except in compliance with the License.
+* You may obtain a copy of the License at
+*
+*     http://www.apache.org/licenses/LICENSE-2.0
+*
+* Unless required by applicable law or agreed to in writing, software
+* distributed under the License is distributed on an "AS IS" BASIS,
+* WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+* See the License for the specific language governing permissions and
+* limitations under the License.
+*/
+
+//! GDB Remote Serial Protocol stub, speaking RSP over the existing serial
+//! port so `target remote /dev/ttyS0` (or QEMU's `-serial` pipe) attaches
+//! to a running kernel. `int3`/debug-exception entry points call
+//! [`break_and_serve`], which parks the CPU in [`serve`] until the debugger
+//! tells it to continue or single-step.
+//!
+//! `m`/`M` addresses and `int3` breakpoint addresses both come from the
+//! debugger and aren't trusted: [`range_is_accessible`] walks the active
+//! page tables the same way `virtio_blk::translate` does before
+//! [`read_memory`]/[`write_memory`] touch anything, and resuming past a
+//! still-installed breakpoint goes through [`resume`], which restores the
+//! original byte, single-steps over it, and only then reinstalls `0xCC` -
+//! otherwise the next fetch at that address would just retrap on the same
+//! `int3`. [`set_phys_mem_offset`] must be called once paging is up and
+//! before the first `m`/`M`/`Z0` packet, mirroring
+//! `virtio_blk::set_phys_mem_offset`.
+
+use crate::serial::SERIAL1;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::page_table::FrameError;
+use x86_64::structures::paging::{PageTable, PageTableFlags};
+use x86_64::VirtAddr;
+
+/// Full x86_64 GDB register set, in the order `g`/`G` packets use.
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct GdbRegisters {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub rsp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rip: u64,
+    pub eflags: u64,
+    pub cs: u64,
+    pub ss: u64,
+    pub ds: u64,
+    pub es: u64,
+    pub fs: u64,
+    pub gs: u64,
+}
+
+const TRAP_FLAG: u64 = 1 << 8;
+
+struct Breakpoint {
+    addr: u64,
+    original_byte: u8,
+}
+
+static BREAKPOINTS: spin::Mutex<Vec<Breakpoint>> = spin::Mutex::new(Vec::new());
+
+/// A resume request that landed exactly on an installed breakpoint's
+/// address, parked by [`resume`] until the forced single-step over the
+/// original instruction reports back to [`break_and_serve`].
+#[derive(Clone, Copy)]
+struct StepOver {
+    addr: u64,
+    resume: StopAction,
+}
+
+static STEP_OVER: spin::Mutex<Option<StepOver>> = spin::Mutex::new(None);
+
+/// Offset of the direct physical memory map, the same value `kernel_main`
+/// passes to `MemoryManager::new`. Needed to walk the active page tables
+/// (their frames are physical addresses) when validating a debugger
+/// address in [`range_is_accessible`].
+static PHYS_MEM_OFFSET: spin::Mutex<Option<u64>> = spin::Mutex::new(None);
+
+/// Records the physical memory offset so [`range_is_accessible`] can walk
+/// the active page tables. Must be called before the first `m`/`M`
+/// packet, once paging is up.
+pub fn set_phys_mem_offset(offset: u64) {
+    *PHYS_MEM_OFFSET.lock() = Some(offset);
+}
+
+/// Nothing to negotiate up front; the stub only becomes active once
+/// `interrupts` routes `#BP`/debug exceptions into [`break_and_serve`].
+/// Exists so `kernel_main` can report the subsystem like every other
+/// boot stage.
+pub fn init() {
+    crate::serial_println!("GDB stub listening on the serial port (attach with `target remote`)");
+}
+
+fn read_byte() -> u8 {
+    SERIAL1.lock().receive()
+}
+
+fn write_byte(byte: u8) {
+    SERIAL1.lock().send(byte);
+}
+
+fn write_bytes(bytes: &[u8]) {
+    let mut serial = SERIAL1.lock();
+    for &b in bytes {
+        serial.send(b);
+    }
+}
+
+fn checksum(payload: &[u8]) -> u8 {
+    payload.iter().fold(0u8, |acc, &b| acc.wrapping_add(b))
+}
+
+/// Reads one `$<payload>#<checksum>` packet, retrying on a bad checksum.
+fn read_packet() -> String {
+    loop {
+        while read_byte() != b'$' {}
+
+        let mut payload = Vec::new();
+        loop {
+            let byte = read_byte();
+            if byte == b'#' {
+                break;
+            }
+            payload.push(byte);
+        }
+
+        let hi = read_byte();
+        let lo = read_byte();
+        let received = hex_pair_to_byte(hi, lo);
+
+        if received == Some(checksum(&payload)) {
+            write_byte(b'+');
+            return String::from_utf8_lossy(&payload).into_owned();
+        } else {
+            write_byte(b'-');
+        }
+    }
+}
+
+fn hex_pair_to_byte(hi: u8, lo: u8) -> Option<u8> {
+    Some((hex_val(hi)? << 4) | hex_val(lo)?)
+}
+
+fn hex_val(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    }
+}
+
+fn send_packet(payload: &str) {
+    let sum = checksum(payload.as_bytes());
+    write_byte(b'$');
+    write_bytes(payload.as_bytes());
+    write_byte(b'#');
+    write_bytes(format!("{:02x}", sum).as_bytes());
+
+    loop {
+        match read_byte() {
+            b'+' => break,
+            b'-' => {
+                write_byte(b'$');
+                write_bytes(payload.as_bytes());
+                write_byte(b'#');
+                write_bytes(format!("{:02x}", sum).as_bytes());
+            }
+            _ => {}
+        }
+    }
+}
+
+fn registers_to_hex(regs: &GdbRegisters) -> String {
+    let words = [
+        regs.rax, regs.rbx, regs.rcx, regs.rdx, regs.rsi, regs.rdi, regs.rbp, regs.rsp,
+        regs.r8, regs.r9, regs.r10, regs.r11, regs.r12, regs.r13, regs.r14, regs.r15,
+        regs.rip, regs.eflags, regs.cs, regs.ss, regs.ds, regs.es, regs.fs, regs.gs,
+    ];
+    let mut out = String::with_capacity(words.len() * 16);
+    for word in words {
+        for byte in word.to_le_bytes() {
+            out.push_str(&format!("{:02x}", byte));
+        }
+    }
+    out
+}
+
+/// Checks, via the active page tables, that every byte of `addr..addr+len`
+/// is mapped (and, when `require_writable`, writable) before
+/// [`read_memory`]/[`write_memory`] dereference any of it. A debugger can
+/// ask for any address it likes; without this, a bad one would page-fault
+/// the kernel instead of coming back as `E14`.
+fn range_is_accessible(addr: u64, len: usize, require_writable: bool) -> bool {
+    if len == 0 {
+        return true;
+    }
+    let Some(offset) = *PHYS_MEM_OFFSET.lock() else {
+        return false;
+    };
+    let Some(last_byte) = addr.checked_add(len as u64 - 1) else {
+        return false;
+    };
+    let Ok(start) = VirtAddr::try_new(addr) else {
+        return false;
+    };
+    let Ok(end) = VirtAddr::try_new(last_byte) else {
+        return false;
+    };
+
+    let mut page = start.align_down(4096u64);
+    let last_page = end.align_down(4096u64);
+    loop {
+        if !page_is_mapped(offset, page, require_writable) {
+            return false;
+        }
+        if page == last_page {
+            return true;
+        }
+        page += 4096u64;
+    }
+}
+
+/// Walks the active four-level page table to check whether `page` is
+/// mapped (and, if `require_writable`, writable), the same way
+/// `virtio_blk::translate` walks it to translate an address instead of
+/// assuming identity mapping.
+fn page_is_mapped(offset: u64, page: VirtAddr, require_writable: bool) -> bool {
+    let (level_4_frame, _) = Cr3::read();
+    let table_indexes = [page.p4_index(), page.p3_index(), page.p2_index(), page.p1_index()];
+    let mut frame = level_4_frame;
+
+    for (depth, &index) in table_indexes.iter().enumerate() {
+        let virt = offset + frame.start_address().as_u64();
+        let table = unsafe { &*(virt as *const PageTable) };
+        let entry = &table[index];
+
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            return false;
+        }
+        if require_writable && !entry.flags().contains(PageTableFlags::WRITABLE) {
+            return false;
+        }
+
+        if depth == 3 {
+            return true;
+        }
+
+        frame = match entry.frame() {
+            Ok(frame) => frame,
+            Err(FrameError::FrameNotPresent) => return false,
+            Err(FrameError::HugeFrame) => return true,
+        };
+    }
+    false
+}
+
+/// Reads `len` bytes starting at `addr` through the current page tables.
+/// Returns `None` if any byte in range isn't mapped so the caller can
+/// answer with `E14` instead of faulting.
+unsafe fn read_memory(addr: u64, len: usize) -> Option<Vec<u8>> {
+    if !range_is_accessible(addr, len, false) {
+        return None;
+    }
+    let mut out = Vec::with_capacity(len);
+    let ptr = addr as *const u8;
+    for i in 0..len {
+        out.push(core::ptr::read_volatile(ptr.add(i)));
+    }
+    Some(out)
+}
+
+unsafe fn write_memory(addr: u64, data: &[u8]) -> bool {
+    if !range_is_accessible(addr, data.len(), true) {
+        return false;
+    }
+    let ptr = addr as *mut u8;
+    for (i, &byte) in data.iter().enumerate() {
+        core::ptr::write_volatile(ptr.add(i), byte);
+    }
+    true
+}
+
+#[derive(Clone, Copy)]
+enum StopAction {
+    Continue,
+    Step,
+}
+
+/// Parses a `G` packet's hex payload (24 little-endian 8-byte words, same
+/// order as [`registers_to_hex`]) and writes it into `regs`. Returns
+/// `false` (leaving `regs` untouched) if the payload is short or not
+/// valid hex.
+fn apply_registers_hex(regs: &mut GdbRegisters, hex: &str) -> bool {
+    const WORD_COUNT: usize = 24;
+    let bytes = hex.as_bytes();
+    if bytes.len() < WORD_COUNT * 16 {
+        return false;
+    }
+
+    let mut words = [0u64; WORD_COUNT];
+    for (i, word) in words.iter_mut().enumerate() {
+        let chunk = &bytes[i * 16..i * 16 + 16];
+        let mut word_bytes = [0u8; 8];
+        for (j, wb) in word_bytes.iter_mut().enumerate() {
+            *wb = match hex_pair_to_byte(chunk[j * 2], chunk[j * 2 + 1]) {
+                Some(b) => b,
+                None => return false,
+            };
+        }
+        *word = u64::from_le_bytes(word_bytes);
+    }
+
+    regs.rax = words[0];
+    regs.rbx = words[1];
+    regs.rcx = words[2];
+    regs.rdx = words[3];
+    regs.rsi = words[4];
+    regs.rdi = words[5];
+    regs.rbp = words[6];
+    regs.rsp = words[7];
+    regs.r8 = words[8];
+    regs.r9 = words[9];
+    regs.r10 = words[10];
+    regs.r11 = words[11];
+    regs.r12 = words[12];
+    regs.r13 = words[13];
+    regs.r14 = words[14];
+    regs.r15 = words[15];
+    regs.rip = words[16];
+    regs.eflags = words[17];
+    regs.cs = words[18];
+    regs.ss = words[19];
+    regs.ds = words[20];
+    regs.es = words[21];
+    regs.fs = words[22];
+    regs.gs = words[23];
+    true
+}
+
+/// Runs the GDB command loop for a single stop event, returning how the
+/// debuggee should resume once the debugger issues `c` or `s`.
+fn serve(regs: &mut GdbRegisters) -> StopAction {
+    loop {
+        let packet = read_packet();
+        let mut chars = packet.chars();
+        match chars.next() {
+            Some('?') => send_packet("S05"),
+            Some('g') => send_packet(&registers_to_hex(regs)),
+            Some('G') => {
+                if apply_registers_hex(regs, &packet[1..]) {
+                    send_packet("OK");
+                } else {
+                    send_packet("E01");
+                }
+            }
+            Some('m') => {
+                let rest = &packet[1..];
+                if let Some((addr_s, len_s)) = rest.split_once(',') {
+                    let addr = u64::from_str_radix(addr_s, 16).unwrap_or(0);
+                    let len = usize::from_str_radix(len_s, 16).unwrap_or(0);
+                    match unsafe { read_memory(addr, len) } {
+                        Some(bytes) => {
+                            let mut hex = String::with_capacity(bytes.len() * 2);
+                            for b in bytes {
+                                hex.push_str(&format!("{:02x}", b));
+                            }
+                            send_packet(&hex);
+                        }
+                        None => send_packet("E14"),
+                    }
+                } else {
+                    send_packet("E01");
+                }
+            }
+            Some('M') => {
+                let rest = &packet[1..];
+                if let Some((header, data_hex)) = rest.split_once(':') {
+                    if let Some((addr_s, _len_s)) = header.split_once(',') {
+                        let addr = u64::from_str_radix(addr_s, 16).unwrap_or(0);
+                        let mut data = Vec::with_capacity(data_hex.len() / 2);
+                        let bytes = data_hex.as_bytes();
+                        let mut i = 0;
+                        while i + 1 < bytes.len() {
+                            if let Some(b) = hex_pair_to_byte(bytes[i], bytes[i + 1]) {
+                                data.push(b);
+                            }
+                            i += 2;
+                        }
+                        if unsafe { write_memory(addr, &data) } {
+                            send_packet("OK");
+                        } else {
+                            send_packet("E14");
+                        }
+                    } else {
+                        send_packet("E01");
+                    }
+                } else {
+                    send_packet("E01");
+                }
+            }
+            Some('Z') => {
+                if packet.starts_with("Z0,") {
+                    match parse_breakpoint_addr(&packet) {
+                        Some(addr) if install_breakpoint(addr) => send_packet("OK"),
+                        Some(_) => send_packet("E14"),
+                        None => send_packet("E01"),
+                    }
+                } else {
+                    send_packet("");
+                }
+            }
+            Some('z') => {
+                if packet.starts_with("z0,") {
+                    match parse_breakpoint_addr(&packet) {
+                        Some(addr) if remove_breakpoint(addr) => send_packet("OK"),
+                        Some(_) => send_packet("E14"),
+                        None => send_packet("E01"),
+                    }
+                } else {
+                    send_packet("");
+                }
+            }
+            Some('c') => return resume(regs, StopAction::Continue),
+            Some('s') => return resume(regs, StopAction::Step),
+            _ => send_packet(""),
+        }
+    }
+}
+
+/// Prepares `regs` to resume as `requested`. If `regs.rip` sits exactly on
+/// an installed breakpoint, the original byte is still overwritten with
+/// `0xCC` there, so resuming directly would just retrap on the same
+/// instruction instead of executing it: this restores the original byte,
+/// forces a single step so that instruction retires, and parks
+/// `requested` in [`STEP_OVER`] for [`break_and_serve`] to pick up and
+/// reinstall the breakpoint once that step completes.
+fn resume(regs: &mut GdbRegisters, requested: StopAction) -> StopAction {
+    if let Some(addr) = breakpoint_at(regs.rip) {
+        restore_original_byte(addr);
+        *STEP_OVER.lock() = Some(StepOver { addr, resume: requested });
+        regs.eflags |= TRAP_FLAG;
+        return StopAction::Step;
+    }
+
+    match requested {
+        StopAction::Continue => regs.eflags &= !TRAP_FLAG,
+        StopAction::Step => regs.eflags |= TRAP_FLAG,
+    }
+    requested
+}
+
+fn parse_breakpoint_addr(packet: &str) -> Option<u64> {
+    let rest = packet.splitn(3, ',').nth(1)?;
+    u64::from_str_radix(rest, 16).ok()
+}
+
+fn breakpoint_at(addr: u64) -> Option<u64> {
+    BREAKPOINTS.lock().iter().find(|bp| bp.addr == addr).map(|bp| bp.addr)
+}
+
+fn restore_original_byte(addr: u64) {
+    if !range_is_accessible(addr, 1, true) {
+        return;
+    }
+    if let Some(bp) = BREAKPOINTS.lock().iter().find(|bp| bp.addr == addr) {
+        unsafe { core::ptr::write_volatile(bp.addr as *mut u8, bp.original_byte) };
+    }
+}
+
+fn reinstall_breakpoint_if_present(addr: u64) {
+    if !range_is_accessible(addr, 1, true) {
+        return;
+    }
+    if BREAKPOINTS.lock().iter().any(|bp| bp.addr == addr) {
+        unsafe { core::ptr::write_volatile(addr as *mut u8, 0xCC) };
+    }
+}
+
+/// Installs a software breakpoint at `addr`, returning `false` (leaving
+/// [`BREAKPOINTS`] untouched) instead of dereferencing `addr` when it isn't
+/// mapped and writable - a `Z0` packet's address comes from the debugger
+/// the same way `m`/`M`'s do, and deserves the same
+/// [`range_is_accessible`] guard [`read_memory`]/[`write_memory`] already
+/// get.
+fn install_breakpoint(addr: u64) -> bool {
+    if !range_is_accessible(addr, 1, true) {
+        return false;
+    }
+    let original = unsafe { core::ptr::read_volatile(addr as *const u8) };
+    unsafe { core::ptr::write_volatile(addr as *mut u8, 0xCC) };
+    BREAKPOINTS.lock().push(Breakpoint {
+        addr,
+        original_byte: original,
+    });
+    true
+}
+
+/// Removes the breakpoint at `addr`, restoring its original byte. Returns
+/// `false` if `addr` isn't mapped and writable, without touching
+/// [`BREAKPOINTS`], so a `z0` packet for an address that's gone unmapped
+/// since it was installed reports `E14` instead of faulting the kernel.
+fn remove_breakpoint(addr: u64) -> bool {
+    if !range_is_accessible(addr, 1, true) {
+        return false;
+    }
+    let mut breakpoints = BREAKPOINTS.lock();
+    if let Some(pos) = breakpoints.iter().position(|bp| bp.addr == addr) {
+        let bp = breakpoints.remove(pos);
+        unsafe { core::ptr::write_volatile(bp.addr as *mut u8, bp.original_byte) };
+    }
+    true
+}
+
+/// Entry point for the `#BP` (`int3`) and debug-exception handlers in
+/// `interrupts` to call once they've saved the interrupted register state
+/// (including `rip`/`rsp`/`eflags`) into `regs`. If this stop is the
+/// single step [`resume`] forced to step over a breakpoint, reinstalls it
+/// and resumes however was actually requested instead of re-entering the
+/// command loop. Otherwise blocks in [`serve`] until the debugger issues
+/// `c` or `s`. Either way, the caller is responsible for writing
+/// `regs.rip`/`regs.eflags` back into the trap frame.
+pub fn break_and_serve(regs: &mut GdbRegisters) {
+    if let Some(step_over) = STEP_OVER.lock().take() {
+        reinstall_breakpoint_if_present(step_over.addr);
+        resume(regs, step_over.resume);
+        return;
+    }
+
+    serve(regs);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn checksum_is_sum_of_payload_bytes_mod_256() {
+        assert_eq!(checksum(b""), 0);
+        assert_eq!(checksum(b"OK"), (b'O' as u8).wrapping_add(b'K'));
+        assert_eq!(checksum(&[0xff, 0x02]), 0x01);
+    }
+
+    #[test_case]
+    fn hex_pair_to_byte_parses_both_cases() {
+        assert_eq!(hex_pair_to_byte(b'0', b'0'), Some(0x00));
+        assert_eq!(hex_pair_to_byte(b'f', b'f'), Some(0xff));
+        assert_eq!(hex_pair_to_byte(b'A', b'B'), Some(0xab));
+        assert_eq!(hex_pair_to_byte(b'g', b'0'), None);
+    }
+
+    #[test_case]
+    fn apply_registers_hex_rejects_short_payload() {
+        let mut regs = GdbRegisters::default();
+        assert!(!apply_registers_hex(&mut regs, "00"));
+    }
+
+    #[test_case]
+    fn apply_registers_hex_round_trips_through_registers_to_hex() {
+        let regs = GdbRegisters {
+            rax: 0x0102030405060708,
+            rip: 0xdeadbeefcafebabe,
+            ..Default::default()
+        };
+        let hex = registers_to_hex(&regs);
+
+        let mut round_tripped = GdbRegisters::default();
+        assert!(apply_registers_hex(&mut round_tripped, &hex));
+        assert_eq!(round_tripped.rax, regs.rax);
+        assert_eq!(round_tripped.rip, regs.rip);
+    }
+}
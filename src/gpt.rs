@@ -0,0 +1,264 @@
+/*
+* Copyright 2023-2024 Juan Miguel Giraldo
+*
+* Licensed under the Apache License, Version 2.0 (the "License");
+* you may not use this file except in compliance with the License.
+* You may obtain a copy of the License at
+*
+*     http://www.apache.org/licenses/LICENSE-2.0
+*
+* Unless required by applicable law or agreed to in writing, software
+* distributed under the License is distributed on an "AS IS" BASIS,
+* WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+* See the License for the specific language governing permissions and
+* limitations under the License.
+*/
+
+//! GUID Partition Table parsing on top of `virtio_blk`, so the VKFS volume
+//! can be located at its real partition offset instead of assumed to start
+//! at block 0. Reads the protective MBR, validates the primary header and
+//! partition array against their CRC32 fields, and falls back to the
+//! backup GPT at the last LBA of the disk if the primary copy is corrupt.
+
+use crate::virtio_blk::{self, SECTOR_SIZE};
+use alloc::vec::Vec;
+
+const GPT_SIGNATURE: [u8; 8] = *b"EFI PART";
+const MBR_LBA: u64 = 0;
+const PRIMARY_HEADER_LBA: u64 = 1;
+
+#[derive(Debug)]
+pub enum GptError {
+    Io,
+    NoProtectiveMbr,
+    BadSignature,
+    HeaderCrcMismatch,
+    EntryArrayCrcMismatch,
+    BothCopiesInvalid,
+}
+
+#[derive(Debug, Clone)]
+pub struct GptPartition {
+    pub type_guid: [u8; 16],
+    pub unique_guid: [u8; 16],
+    pub start_lba: u64,
+    pub end_lba: u64,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct GptHeaderRaw {
+    signature: [u8; 8],
+    revision: u32,
+    header_size: u32,
+    header_crc32: u32,
+    reserved: u32,
+    current_lba: u64,
+    backup_lba: u64,
+    first_usable_lba: u64,
+    last_usable_lba: u64,
+    disk_guid: [u8; 16],
+    partition_entry_lba: u64,
+    num_partition_entries: u32,
+    partition_entry_size: u32,
+    partition_array_crc32: u32,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct GptEntryRaw {
+    type_guid: [u8; 16],
+    unique_guid: [u8; 16],
+    start_lba: u64,
+    end_lba: u64,
+    attributes: u64,
+    name: [u16; 36],
+}
+
+static CRC32_TABLE: [u32; 256] = build_crc32_table();
+
+const fn build_crc32_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut j = 0;
+        while j < 8 {
+            if crc & 1 != 0 {
+                crc = (crc >> 1) ^ 0xEDB88320;
+            } else {
+                crc >>= 1;
+            }
+            j += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc: u32 = 0xFFFFFFFF;
+    for &byte in data {
+        let index = ((crc ^ byte as u32) & 0xff) as usize;
+        crc = (crc >> 8) ^ CRC32_TABLE[index];
+    }
+    crc ^ 0xFFFFFFFF
+}
+
+fn read_sector(lba: u64) -> Result<[u8; SECTOR_SIZE], GptError> {
+    let mut buf = [0u8; SECTOR_SIZE];
+    virtio_blk::read_block_absolute(lba, &mut buf).map_err(|_| GptError::Io)?;
+    Ok(buf)
+}
+
+fn parse_header(sector: &[u8; SECTOR_SIZE]) -> Result<GptHeaderRaw, GptError> {
+    if &sector[0..8] != &GPT_SIGNATURE {
+        return Err(GptError::BadSignature);
+    }
+
+    let header = unsafe { core::ptr::read_unaligned(sector.as_ptr() as *const GptHeaderRaw) };
+
+    let header_size = header.header_size as usize;
+    if header_size < core::mem::size_of::<GptHeaderRaw>() || header_size > SECTOR_SIZE {
+        return Err(GptError::HeaderCrcMismatch);
+    }
+
+    let mut crc_check = Vec::with_capacity(header_size);
+    crc_check.extend_from_slice(&sector[..header_size]);
+    crc_check[16..20].copy_from_slice(&0u32.to_le_bytes());
+
+    if crc32(&crc_check) != header.header_crc32 {
+        return Err(GptError::HeaderCrcMismatch);
+    }
+
+    Ok(header)
+}
+
+/// Upper bound on a partition entry array's total size, comfortably above
+/// any real GPT (128 entries of 128 bytes is the common case) but far below
+/// what would strain a boot-time allocation. `entry_size`/`entry_count` are
+/// CRC-checked but not otherwise validated before this point, so a
+/// corrupted-but-CRC-valid header could otherwise drive an unbounded
+/// `Vec::with_capacity`.
+const MAX_ENTRY_ARRAY_BYTES: usize = 4 * 1024 * 1024;
+
+fn validate_entry_array(header: &GptHeaderRaw) -> Result<Vec<GptPartition>, GptError> {
+    let entry_size = header.partition_entry_size as usize;
+    let entry_count = header.num_partition_entries as usize;
+
+    if entry_size < core::mem::size_of::<GptEntryRaw>() {
+        return Err(GptError::EntryArrayCrcMismatch);
+    }
+
+    let total_bytes = entry_size
+        .checked_mul(entry_count)
+        .filter(|&bytes| bytes <= MAX_ENTRY_ARRAY_BYTES)
+        .ok_or(GptError::EntryArrayCrcMismatch)?;
+    let sectors_needed = (total_bytes + SECTOR_SIZE - 1) / SECTOR_SIZE;
+
+    let mut array = Vec::with_capacity(sectors_needed * SECTOR_SIZE);
+    for i in 0..sectors_needed {
+        let sector = read_sector(header.partition_entry_lba + i as u64)?;
+        array.extend_from_slice(&sector);
+    }
+    array.truncate(total_bytes);
+
+    if crc32(&array) != header.partition_array_crc32 {
+        return Err(GptError::EntryArrayCrcMismatch);
+    }
+
+    let mut partitions = Vec::new();
+    for i in 0..entry_count {
+        let offset = i * entry_size;
+        let entry = unsafe {
+            core::ptr::read_unaligned(array[offset..].as_ptr() as *const GptEntryRaw)
+        };
+        if entry.type_guid == [0u8; 16] {
+            continue;
+        }
+        partitions.push(GptPartition {
+            type_guid: entry.type_guid,
+            unique_guid: entry.unique_guid,
+            start_lba: entry.start_lba,
+            end_lba: entry.end_lba,
+        });
+    }
+
+    Ok(partitions)
+}
+
+fn try_read_table(header_lba: u64) -> Result<Vec<GptPartition>, GptError> {
+    let header_sector = read_sector(header_lba)?;
+    let header = parse_header(&header_sector)?;
+    validate_entry_array(&header)
+}
+
+/// Reads the protective MBR and the primary GPT, validating both the
+/// header and partition-entry-array CRC32s. Falls back to the backup GPT
+/// at the last LBA of the disk when the primary copy fails validation.
+pub fn read_partitions(disk_last_lba: u64) -> Result<Vec<GptPartition>, GptError> {
+    let mbr = read_sector(MBR_LBA)?;
+    if mbr[450] != 0xEE {
+        return Err(GptError::NoProtectiveMbr);
+    }
+
+    match try_read_table(PRIMARY_HEADER_LBA) {
+        Ok(partitions) => Ok(partitions),
+        Err(_) => try_read_table(disk_last_lba).map_err(|_| GptError::BothCopiesInvalid),
+    }
+}
+
+const VKFS_TYPE_GUID: [u8; 16] = [
+    0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08,
+    0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e, 0x0f, 0x10,
+];
+
+/// Picks the VKFS partition out of a discovered partition list by type
+/// GUID, handing back its starting LBA for the filesystem layer to mount
+/// from instead of assuming block 0.
+pub fn find_vkfs_partition(partitions: &[GptPartition]) -> Option<&GptPartition> {
+    partitions.iter().find(|p| p.type_guid == VKFS_TYPE_GUID)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn crc32_of_empty_input_is_zero() {
+        assert_eq!(crc32(&[]), 0);
+    }
+
+    #[test_case]
+    fn crc32_matches_known_check_value() {
+        // The standard CRC-32 (IEEE 802.3) check value for the ASCII string
+        // "123456789", per the Rocksoft CRC catalogue.
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+    }
+
+    #[test_case]
+    fn entry_array_byte_count_rejects_oversized_header_fields() {
+        let huge_header = GptHeaderRaw {
+            signature: GPT_SIGNATURE,
+            revision: 0,
+            header_size: 0,
+            header_crc32: 0,
+            reserved: 0,
+            current_lba: 0,
+            backup_lba: 0,
+            first_usable_lba: 0,
+            last_usable_lba: 0,
+            disk_guid: [0; 16],
+            partition_entry_lba: 2,
+            num_partition_entries: u32::MAX,
+            partition_entry_size: u32::MAX,
+            partition_array_crc32: 0,
+        };
+
+        assert!(matches!(
+            validate_entry_array(&huge_header),
+            Err(GptError::EntryArrayCrcMismatch)
+        ));
+    }
+}
@@ -0,0 +1,258 @@
+/*
+* Copyright 2023-2024 Juan Miguel Giraldo
+*
+* Licensed under the Apache License, Version 2.0 (the "License");
+* you may not use this file except in compliance with the License.
+* You may obtain a copy of the License at
+*
+*     http://www.apache.org/licenses/LICENSE-2.0
+*
+* Unless required by applicable law or agreed to in writing, software
+* distributed under the License is distributed on an "AS IS" BASIS,
+* WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+* See the License for the specific language governing permissions and
+* limitations under the License.
+*/
+
+//! IDT setup. `#BP` (`int3`) and `#DB` (debug exception) are routed through
+//! naked trampolines that save the full GPR set before handing control to
+//! [`gdb::break_and_serve`], since the `x86-interrupt` ABI only hands a
+//! handler the interrupt frame (`rip`/`cs`/`rflags`), not the
+//! general-purpose registers a debugger needs to inspect. `acpi`/`apic`
+//! move IRQ delivery off the legacy PICs onto the Local/IO APIC at
+//! runtime; [`end_of_interrupt`] is the single place that decides which
+//! one to acknowledge so the keyboard and timer keep ticking either way.
+//!
+//! Chosen vectors line up with the legacy PIC remap window so `apic`'s IO
+//! APIC redirection entries can route onto the same IDT slots.
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, Ordering};
+use lazy_static::lazy_static;
+use x86_64::instructions::port::Port;
+use x86_64::structures::idt::InterruptDescriptorTable;
+use x86_64::VirtAddr;
+
+use crate::apic;
+use crate::gdb::GdbRegisters;
+
+const PIC1_COMMAND: u16 = 0x20;
+const PIC_EOI: u8 = 0x20;
+
+const APIC_EOI_REGISTER: usize = 0xb0;
+
+/// Flipped once `apic::init` has successfully switched interrupt delivery
+/// off the legacy PICs, so [`end_of_interrupt`] knows which controller to
+/// acknowledge.
+static USING_APIC: AtomicBool = AtomicBool::new(false);
+
+/// Called by `apic::init` once the Local/IO APIC path is live.
+pub fn set_using_apic(using_apic: bool) {
+    USING_APIC.store(using_apic, Ordering::SeqCst);
+}
+
+/// Consulted by `time::init` to decide whether to arm the APIC timer or
+/// fall back to programming the PIT.
+pub fn is_using_apic() -> bool {
+    USING_APIC.load(Ordering::SeqCst)
+}
+
+/// Acknowledges the timer (IRQ0) and keyboard (IRQ1) interrupts VEKOS
+/// currently handles, both of which live on the primary PIC, or on the
+/// Local APIC once `apic::init` has masked the PICs and switched delivery
+/// over.
+fn end_of_interrupt() {
+    if USING_APIC.load(Ordering::SeqCst) {
+        let base = apic::local_apic_base();
+        unsafe { apic::write_local_apic(base, APIC_EOI_REGISTER, 0) };
+    } else {
+        unsafe { Port::<u8>::new(PIC1_COMMAND).write(PIC_EOI) };
+    }
+}
+
+lazy_static! {
+    static ref IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        idt.double_fault.set_handler_fn(double_fault_handler);
+        idt.page_fault.set_handler_fn(page_fault_handler);
+        unsafe {
+            idt.breakpoint.set_handler_addr(VirtAddr::new(breakpoint_trampoline as u64));
+            idt.debug.set_handler_addr(VirtAddr::new(debug_trampoline as u64));
+        }
+        idt[apic::TIMER_VECTOR as usize].set_handler_fn(timer_interrupt_handler);
+        idt[apic::KEYBOARD_VECTOR as usize].set_handler_fn(keyboard_interrupt_handler);
+        idt
+    };
+}
+
+pub fn init_idt() {
+    IDT.load();
+}
+
+extern "x86-interrupt" fn double_fault_handler(
+    stack_frame: x86_64::structures::idt::InterruptStackFrame,
+) -> ! {
+    panic!("DOUBLE FAULT\n{:#?}", stack_frame);
+}
+
+extern "x86-interrupt" fn page_fault_handler(
+    stack_frame: x86_64::structures::idt::InterruptStackFrame,
+    error_code: x86_64::structures::idt::PageFaultErrorCode,
+) {
+    let fault_addr = x86_64::registers::control::Cr2::read();
+    crate::serial_println!(
+        "PAGE FAULT at {:?}, error code {:?}\n{:#?}",
+        fault_addr, error_code, stack_frame
+    );
+}
+
+extern "x86-interrupt" fn timer_interrupt_handler(
+    _stack_frame: x86_64::structures::idt::InterruptStackFrame,
+) {
+    crate::time::SYSTEM_TIME.tick();
+    end_of_interrupt();
+}
+
+extern "x86-interrupt" fn keyboard_interrupt_handler(
+    _stack_frame: x86_64::structures::idt::InterruptStackFrame,
+) {
+    let mut port = Port::<u8>::new(0x60);
+    let _scancode: u8 = unsafe { port.read() };
+    end_of_interrupt();
+}
+
+/// Raw layout the naked trampolines leave on the stack: fifteen pushed
+/// GPRs (in reverse push order, since the stack grows down) followed by
+/// the CPU-provided `rip`/`cs`/`rflags`/`rsp`/`ss` iret frame, which
+/// 64-bit mode pushes in full even without a privilege-level change.
+#[repr(C)]
+struct TrapFrame {
+    r15: u64,
+    r14: u64,
+    r13: u64,
+    r12: u64,
+    r11: u64,
+    r10: u64,
+    r9: u64,
+    r8: u64,
+    rbp: u64,
+    rdi: u64,
+    rsi: u64,
+    rdx: u64,
+    rcx: u64,
+    rbx: u64,
+    rax: u64,
+    rip: u64,
+    cs: u64,
+    rflags: u64,
+    rsp: u64,
+    ss: u64,
+}
+
+/// Called from the naked trampolines with a pointer to the saved register
+/// block. Builds a [`GdbRegisters`], runs the GDB command loop, then
+/// writes `rip`/`eflags` back into the trap frame - `rip` because a `G`
+/// packet may have set it outright, and `eflags` so a
+/// requested single-step (the trap flag) takes effect once the trampoline
+/// `iretq`s.
+fn run_gdb_stop(frame: &mut TrapFrame) {
+    let mut regs = GdbRegisters {
+        rax: frame.rax,
+        rbx: frame.rbx,
+        rcx: frame.rcx,
+        rdx: frame.rdx,
+        rsi: frame.rsi,
+        rdi: frame.rdi,
+        rbp: frame.rbp,
+        rsp: frame.rsp,
+        r8: frame.r8,
+        r9: frame.r9,
+        r10: frame.r10,
+        r11: frame.r11,
+        r12: frame.r12,
+        r13: frame.r13,
+        r14: frame.r14,
+        r15: frame.r15,
+        rip: frame.rip,
+        eflags: frame.rflags,
+        cs: frame.cs,
+        ss: frame.ss,
+        ..Default::default()
+    };
+
+    crate::gdb::break_and_serve(&mut regs);
+
+    frame.rip = regs.rip;
+    frame.rflags = regs.eflags;
+}
+
+/// `#BP` (`int3`) entry point. The CPU leaves `rip` one byte past the
+/// `0xCC` it just executed; rewind it to the breakpoint's actual address
+/// before running the GDB command loop, so both the reported stop address
+/// and `gdb`'s own step-over-and-reinstall logic see where the breakpoint
+/// was really set.
+#[no_mangle]
+extern "C" fn handle_breakpoint_trap(frame: *mut TrapFrame) {
+    let frame = unsafe { &mut *frame };
+    frame.rip = frame.rip.wrapping_sub(1);
+    run_gdb_stop(frame);
+}
+
+/// `#DB` (debug exception, e.g. single-step) entry point. Unlike `#BP`,
+/// `rip` already points at the next instruction to execute, so no rewind
+/// is needed.
+#[no_mangle]
+extern "C" fn handle_debug_trap(frame: *mut TrapFrame) {
+    let frame = unsafe { &mut *frame };
+    run_gdb_stop(frame);
+}
+
+macro_rules! gdb_trampoline {
+    ($name:ident, $handler:ident) => {
+        #[naked]
+        extern "C" fn $name() -> ! {
+            unsafe {
+                asm!(
+                    "push rax",
+                    "push rbx",
+                    "push rcx",
+                    "push rdx",
+                    "push rsi",
+                    "push rdi",
+                    "push rbp",
+                    "push r8",
+                    "push r9",
+                    "push r10",
+                    "push r11",
+                    "push r12",
+                    "push r13",
+                    "push r14",
+                    "push r15",
+                    "mov rdi, rsp",
+                    "call {handler}",
+                    "pop r15",
+                    "pop r14",
+                    "pop r13",
+                    "pop r12",
+                    "pop r11",
+                    "pop r10",
+                    "pop r9",
+                    "pop r8",
+                    "pop rbp",
+                    "pop rdi",
+                    "pop rsi",
+                    "pop rdx",
+                    "pop rcx",
+                    "pop rbx",
+                    "pop rax",
+                    "iretq",
+                    handler = sym $handler,
+                    options(noreturn)
+                );
+            }
+        }
+    };
+}
+
+gdb_trampoline!(breakpoint_trampoline, handle_breakpoint_trap);
+gdb_trampoline!(debug_trampoline, handle_debug_trap);
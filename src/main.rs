@@ -29,6 +29,8 @@ pub mod serial;
 pub mod signals;
 pub mod graphics_hal;
 pub mod page_table_cache;
+pub mod boot_protocol;
+pub mod gdb;
 use x86_64::instructions::port::Port;
 use spin::Mutex;
 use alloc::string::String;
@@ -82,6 +84,12 @@ mod process;
 mod scheduler;
 mod time;
 mod priority;
+mod virtio_blk;
+mod gpt;
+mod acpi;
+mod apic;
+pub mod sandbox;
+pub mod checkpoint;
 
 pub const PAGE_SIZE: usize = 4096;
 pub const MAX_ORDER: usize = 11;
@@ -145,13 +153,47 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     BootSplash::print_boot_message("Initializing Global Descriptor Table...", BootMessageType::Info);
     gdt::init();
     BootSplash::print_boot_message("GDT initialization complete", BootMessageType::Success);
-    
-    BootSplash::print_boot_message("Initializing memory management...", BootMessageType::Info);
+
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+
+    BootSplash::print_boot_message("Initializing IDT...", BootMessageType::Info);
+    interrupts::init_idt();
+    match boot_verifier.verify_stage_vmk(BootStage::IDTLoaded) {
+        Ok(proof) => {
+            serial_println!("IDT verification proof generated: op_id={}", proof.op_id);
+        },
+        Err(e) => {
+            BootSplash::print_boot_message("IDT verification failed!", BootMessageType::Error);
+            boot_verifier.log_error("IDT verification failed");
+            panic!("IDT initialization failed with verification error: {:?}", e);
+        }
+    }
+    BootSplash::print_boot_message("IDT initialization complete", BootMessageType::Success);
+
+    // ACPI/MADT walking dereferences physical firmware addresses, so both
+    // the direct physical map offset and a real IDT (in case one of those
+    // dereferences faults) need to be in place first.
+    BootSplash::print_boot_message("Parsing ACPI tables...", BootMessageType::Info);
+    acpi::set_phys_mem_offset(phys_mem_offset.as_u64());
+    apic::set_phys_mem_offset(phys_mem_offset.as_u64());
+    match acpi::init() {
+        Ok(topology) => {
+            serial_println!("ACPI: {} CPU(s) detected, IO APIC base: {:?}",
+                topology.cpu_count(), topology.io_apic_base);
+            apic::init(&topology);
+            BootSplash::print_boot_message("ACPI/APIC initialization complete", BootMessageType::Success);
+        }
+        Err(e) => {
+            serial_println!("ACPI initialization failed: {:?}, staying on legacy PICs", e);
+            BootSplash::print_boot_message("ACPI tables not found, using legacy PIC", BootMessageType::Info);
+        }
+    }
+
+    BootSplash::print_boot_message("Initializing memory management...", BootMessageType::Info);
     let mut memory_manager = unsafe { MemoryManager::new(phys_mem_offset, &boot_info.memory_map) };
     BootSplash::print_boot_message("Memory management initialized", BootMessageType::Success);
-    
-    
+
+
     BootSplash::print_boot_message("Initializing heap...", BootMessageType::Info);
     let mut mapper = unsafe { memory_manager.get_mapper() };
     let mut frame_allocator = unsafe { memory_manager.get_frame_allocator() };
@@ -179,19 +221,10 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     hash::init();
     BootSplash::print_boot_message("Hash initialization complete", BootMessageType::Success);
 
-    BootSplash::print_boot_message("Initializing IDT...", BootMessageType::Info);
-    interrupts::init_idt();
-    match boot_verifier.verify_stage_vmk(BootStage::IDTLoaded) {
-        Ok(proof) => {
-            serial_println!("IDT verification proof generated: op_id={}", proof.op_id);
-        },
-        Err(e) => {
-            BootSplash::print_boot_message("IDT verification failed!", BootMessageType::Error);
-            boot_verifier.log_error("IDT verification failed");
-            panic!("IDT initialization failed with verification error: {:?}", e);
-        }
-    }    
-    BootSplash::print_boot_message("IDT initialization complete", BootMessageType::Success);
+    BootSplash::print_boot_message("Initializing GDB stub...", BootMessageType::Info);
+    gdb::set_phys_mem_offset(phys_mem_offset.as_u64());
+    gdb::init();
+    BootSplash::print_boot_message("GDB stub initialization complete", BootMessageType::Success);
 
     serial_println!("Testing keyboard interrupt system...");
     unsafe {
@@ -237,6 +270,44 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
         core::hint::spin_loop();
     }
 
+    BootSplash::print_boot_message("Initializing virtio block device...", BootMessageType::Info);
+    virtio_blk::set_phys_mem_offset(phys_mem_offset.as_u64());
+    match virtio_blk::init() {
+        Ok(()) => {
+            BootSplash::print_boot_message("virtio-blk device ready", BootMessageType::Success);
+        }
+        Err(e) => {
+            serial_println!("virtio-blk unavailable: {:?}", e);
+            BootSplash::print_boot_message("virtio-blk device not found, using in-memory fs state", BootMessageType::Info);
+        }
+    }
+
+    if let Some(capacity) = virtio_blk::capacity_sectors() {
+        BootSplash::print_boot_message("Reading GPT partition table...", BootMessageType::Info);
+        match gpt::read_partitions(capacity.saturating_sub(1)) {
+            Ok(partitions) => {
+                serial_println!("GPT: found {} partition(s)", partitions.len());
+                for p in &partitions {
+                    serial_println!("  partition: start_lba={} end_lba={}", p.start_lba, p.end_lba);
+                }
+                match gpt::find_vkfs_partition(&partitions) {
+                    Some(vkfs_partition) => {
+                        serial_println!("VKFS partition located at LBA {}", vkfs_partition.start_lba);
+                        virtio_blk::set_partition_offset(vkfs_partition.start_lba);
+                        BootSplash::print_boot_message("GPT partition table verified", BootMessageType::Success);
+                    }
+                    None => {
+                        serial_println!("No VKFS partition found in GPT, assuming block 0");
+                    }
+                }
+            }
+            Err(e) => {
+                serial_println!("GPT validation failed: {:?}, assuming block 0", e);
+                BootSplash::print_boot_message("GPT partition table invalid!", BootMessageType::Error);
+            }
+        }
+    }
+
     BootSplash::print_boot_message("Initializing filesystem...", BootMessageType::Info);
     fs::init();
     let proof_storage = proof_storage::PROOF_STORAGE.lock();
@@ -273,23 +344,47 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     BootSplash::print_boot_message("Initial process complete", BootMessageType::Success);
 
     serial_println!("Starting framebuffer initialization...");
-    let framebuffer_info = framebuffer::FramebufferInfo {
-        width: 800,
-        height: 600,
-        pitch: 800 * 4,
-        bpp: 32,
-        memory_model: 1,
-        red_mask_size: 8,
-        red_mask_pos: 16,
-        green_mask_size: 8,
-        green_mask_pos: 8,
-        blue_mask_size: 8,
-        blue_mask_pos: 0,
-        page_flip_supported: true,
-        current_page: 0,
+    let boot_framebuffer = boot_protocol::discover_framebuffer();
+    if boot_framebuffer.is_some() {
+        serial_println!("Framebuffer geometry supplied by Limine boot protocol");
+    } else {
+        serial_println!("No Limine framebuffer response, falling back to bootloader defaults");
+    }
+
+    let framebuffer_info = match &boot_framebuffer {
+        Some(fb) => framebuffer::FramebufferInfo {
+            width: fb.width,
+            height: fb.height,
+            pitch: fb.pitch,
+            bpp: fb.bpp as u32,
+            memory_model: 1,
+            red_mask_size: fb.red_mask_size,
+            red_mask_pos: fb.red_mask_pos,
+            green_mask_size: fb.green_mask_size,
+            green_mask_pos: fb.green_mask_pos,
+            blue_mask_size: fb.blue_mask_size,
+            blue_mask_pos: fb.blue_mask_pos,
+            page_flip_supported: true,
+            current_page: 0,
+        },
+        None => framebuffer::FramebufferInfo {
+            width: 800,
+            height: 600,
+            pitch: 800 * 4,
+            bpp: 32,
+            memory_model: 1,
+            red_mask_size: 8,
+            red_mask_pos: 16,
+            green_mask_size: 8,
+            green_mask_pos: 8,
+            blue_mask_size: 8,
+            blue_mask_pos: 0,
+            page_flip_supported: true,
+            current_page: 0,
+        },
     };
 
-    let physical_buffer = 0xfd000000;
+    let physical_buffer = boot_framebuffer.as_ref().map_or(0xfd000000, |fb| fb.physical_address);
     let mut mm_lock = MEMORY_MANAGER.lock();
     if let Some(ref mut mm) = *mm_lock {
         serial_println!("Mapping framebuffer memory...");
@@ -323,11 +418,11 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 
     serial_println!("Starting graphics HAL initialization...");
     let graphics_config = graphics_hal::FramebufferConfig {
-        width: 800,
-        height: 600,
-        pitch: 800 * 4,
-        bpp: 32,
-        physical_buffer: 0xfd000000,
+        width: framebuffer_info.width,
+        height: framebuffer_info.height,
+        pitch: framebuffer_info.pitch,
+        bpp: framebuffer_info.bpp,
+        physical_buffer,
     };
     let mut graphics = graphics_hal::GraphicsHAL::new(graphics_config);
     if let Err(_) = graphics.init_double_buffering() {
@@ -0,0 +1,99 @@
+/*
+* Copyright 2023-2024 Juan Miguel Giraldo
+*
+* Licensed under the Apache License, Version 2.0 (the "License");
+* you may not use this file except in compliance with the License.
+* You may obtain a copy of the License at
+*
+*     http://www.apache.org/licenses/LICENSE-2.0
+*
+* Unless required by applicable law or agreed to in writing, software
+* distributed under the License is distributed on an "AS IS" BASIS,
+* WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+* See the License for the specific language governing permissions and
+* limitations under the License.
+*/
+
+//! Shared Merkle tree building block. Anything that needs to fold a set of
+//! byte blobs into one tamper-evident root - `checkpoint`'s page sets,
+//! `boot_verification`'s stage log - hashes its leaves with [`hash_leaf`]
+//! and reduces them with [`root`], rather than reimplementing its own
+//! pairwise-combine loop.
+
+use alloc::vec::Vec;
+
+pub type Hash = [u8; 32];
+
+/// Hashes a single leaf's bytes with `crate::hash`'s SHA-256, not a
+/// non-cryptographic hash a forged leaf could be fitted to by hand.
+pub fn hash_leaf(data: &[u8]) -> Hash {
+    crate::hash::sha256(data)
+}
+
+fn combine(left: &Hash, right: &Hash) -> Hash {
+    let mut buf = [0u8; 64];
+    buf[..32].copy_from_slice(left);
+    buf[32..].copy_from_slice(right);
+    hash_leaf(&buf)
+}
+
+/// Reduces a set of already-hashed leaves to a single root by repeatedly
+/// combining adjacent pairs, carrying an unpaired last leaf up to the next
+/// level unchanged. Returns the zero hash for an empty leaf set.
+pub fn root(leaves: &[Hash]) -> Hash {
+    if leaves.is_empty() {
+        return [0u8; 32];
+    }
+
+    let mut level = leaves.to_vec();
+    while level.len() > 1 {
+        let mut next = Vec::with_capacity((level.len() + 1) / 2);
+        let mut iter = level.chunks(2);
+        for pair in &mut iter {
+            if pair.len() == 2 {
+                next.push(combine(&pair[0], &pair[1]));
+            } else {
+                next.push(pair[0]);
+            }
+        }
+        level = next;
+    }
+    level[0]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn root_of_empty_leaf_set_is_zero_hash() {
+        assert_eq!(root(&[]), [0u8; 32]);
+    }
+
+    #[test_case]
+    fn root_of_single_leaf_is_that_leaf() {
+        let leaf = hash_leaf(b"one leaf");
+        assert_eq!(root(&[leaf]), leaf);
+    }
+
+    #[test_case]
+    fn root_is_order_sensitive() {
+        let a = hash_leaf(b"a");
+        let b = hash_leaf(b"b");
+        assert_ne!(root(&[a, b]), root(&[b, a]));
+    }
+
+    #[test_case]
+    fn root_is_deterministic() {
+        let leaves: Vec<Hash> = [b"a", b"b", b"c"].iter().map(|d| hash_leaf(*d)).collect();
+        assert_eq!(root(&leaves), root(&leaves));
+    }
+
+    #[test_case]
+    fn differing_leaf_set_changes_the_root() {
+        let original: Vec<Hash> = [b"a", b"b", b"c"].iter().map(|d| hash_leaf(*d)).collect();
+        let mut tampered = original.clone();
+        tampered[1] = hash_leaf(b"tampered");
+        assert_ne!(root(&original), root(&tampered));
+    }
+}
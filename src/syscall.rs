@@ -0,0 +1,94 @@
+/*
+* Copyright 2023-2024 Juan Miguel Giraldo
+*
+* Licensed under the Apache License, Version 2.0 (the "License");
+* you may not use this file except in compliance with the License.
+* You may obtain a copy of the License at
+*
+*     http://www.apache.org/licenses/LICENSE-2.0
+*
+* Unless required by applicable law or agreed to in writing, software
+* distributed under the License is distributed on an "AS IS" BASIS,
+* WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+* See the License for the specific language governing permissions and
+* limitations under the License.
+*/
+
+//! Syscall dispatch. [`dispatch`] is the single entry point every syscall
+//! path funnels through, so it's also where [`sandbox::check`] gets
+//! consulted before a call is allowed to run - a process can only be
+//! confined here, at the boundary, rather than by asking call sites to
+//! remember to check themselves.
+
+use crate::sandbox::{self, Pid, PolicyDecision, SyscallNumber, SyscallPolicy};
+
+pub const SYS_SANDBOX_TIGHTEN: SyscallNumber = 500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyscallError {
+    /// Denied by the calling process's sandbox policy.
+    Sandboxed,
+    /// Denied by the calling process's sandbox policy under
+    /// `DefaultAction::Kill`; `dispatch` does not end the process itself.
+    SandboxedKill,
+    /// Unrecognized syscall number.
+    NoSuchSyscall,
+}
+
+pub fn init() {
+    crate::serial_println!("syscall: dispatch table ready");
+}
+
+/// Entry point every syscall path (interrupt-gate `int 0x80`, `syscall`
+/// instruction, whichever VEKOS ends up using) should call into. Checks
+/// `pid`'s sandbox policy first; anything denied never reaches
+/// [`route`]. A [`PolicyDecision::DeniedKillProcess`] is reported back to
+/// the caller rather than acted on directly here, since tearing down a
+/// process is `process`/`scheduler`'s job, not dispatch's.
+pub fn dispatch(pid: Pid, syscall: SyscallNumber, args: [u64; 4]) -> Result<u64, SyscallError> {
+    match sandbox::check(pid, syscall) {
+        PolicyDecision::Allowed => route(pid, syscall, args),
+        // `sandbox::check` already logs the denial over serial and
+        // records it in `sandbox::denied_attempts`; turning that into
+        // an `OperationProof` in `VERIFICATION_REGISTRY` needs that
+        // registry's proof-construction API, which isn't part of this
+        // checkout.
+        PolicyDecision::DeniedWithError => Err(SyscallError::Sandboxed),
+        PolicyDecision::DeniedKillProcess => Err(SyscallError::SandboxedKill),
+    }
+}
+
+/// Syscalls `dispatch` handles directly rather than forwarding into the
+/// rest of the kernel. Currently just the one that lets a process install
+/// or tighten its own sandbox policy.
+fn route(pid: Pid, syscall: SyscallNumber, args: [u64; 4]) -> Result<u64, SyscallError> {
+    match syscall {
+        SYS_SANDBOX_TIGHTEN => {
+            sandbox::tighten(pid, policy_from_args(args));
+            Ok(0)
+        }
+        _ => Err(SyscallError::NoSuchSyscall),
+    }
+}
+
+/// Decodes a [`SyscallPolicy`] from a syscall's raw argument registers.
+/// `args[0]` is a bitmask over syscall numbers 0-63 (unset bits get
+/// denied); syscalls above 63 aren't reachable through this minimal
+/// encoding yet; `args[1]` is the `DefaultAction` for everything outside
+/// that range (0 = allow, 1 = deny, 2 = kill).
+fn policy_from_args(args: [u64; 4]) -> SyscallPolicy {
+    let mut policy = SyscallPolicy::unrestricted();
+    for syscall in 0..64 {
+        if args[0] & (1 << syscall) == 0 {
+            policy.deny(syscall);
+        }
+    }
+
+    let default_action = match args[1] {
+        1 => crate::sandbox::DefaultAction::Deny,
+        2 => crate::sandbox::DefaultAction::Kill,
+        _ => crate::sandbox::DefaultAction::Allow,
+    };
+    policy.set_default_action(default_action);
+    policy
+}
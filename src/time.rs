@@ -0,0 +1,74 @@
+/*
+* Copyright 2023-2024 Juan Miguel Giraldo
+*
+* Licensed under the Apache License, Version 2.0 (the "License");
+* you may not use this file except in compliance with the License.
+* You may obtain a copy of the License at
+*
+*     http://www.apache.org/licenses/LICENSE-2.0
+*
+* Unless required by applicable law or agreed to in writing, software
+* distributed under the License is distributed on an "AS IS" BASIS,
+* WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+* See the License for the specific language governing permissions and
+* limitations under the License.
+*/
+
+//! System tick counter, driven by whichever timer `interrupts` currently
+//! has wired to its vector: the PIT by default, or the APIC timer once
+//! `apic::init` has switched interrupt delivery over.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::port::Port;
+
+const PIT_FREQUENCY_HZ: u32 = 1_193_182;
+const PIT_CHANNEL0: u16 = 0x40;
+const PIT_COMMAND: u16 = 0x43;
+const PIT_SET_CHANNEL0_RATE_GENERATOR: u8 = 0x36;
+
+/// Fixed APIC timer initial count. It is not calibrated against a
+/// reference clock, so the resulting tick rate depends on the APIC timer's
+/// input frequency and need not match the PIT's 100Hz.
+const APIC_TIMER_INITIAL_COUNT: u32 = 10_000_000;
+
+pub struct SystemTime {
+    ticks: AtomicU64,
+}
+
+impl SystemTime {
+    const fn new() -> Self {
+        Self {
+            ticks: AtomicU64::new(0),
+        }
+    }
+
+    pub fn ticks(&self) -> u64 {
+        self.ticks.load(Ordering::SeqCst)
+    }
+
+    pub fn tick(&self) {
+        self.ticks.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+pub static SYSTEM_TIME: SystemTime = SystemTime::new();
+
+fn program_pit(frequency_hz: u32) {
+    let divisor = (PIT_FREQUENCY_HZ / frequency_hz) as u16;
+    unsafe {
+        Port::<u8>::new(PIT_COMMAND).write(PIT_SET_CHANNEL0_RATE_GENERATOR);
+        Port::<u8>::new(PIT_CHANNEL0).write((divisor & 0xff) as u8);
+        Port::<u8>::new(PIT_CHANNEL0).write((divisor >> 8) as u8);
+    }
+}
+
+/// Arms the scheduler tick source: the APIC timer in place of the PIT once
+/// `acpi`/`apic` have switched interrupt delivery over, otherwise the PIT
+/// at 100Hz exactly as before.
+pub fn init() {
+    if crate::interrupts::is_using_apic() {
+        crate::apic::arm_timer(APIC_TIMER_INITIAL_COUNT);
+    } else {
+        program_pit(100);
+    }
+}
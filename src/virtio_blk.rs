@@ -0,0 +1,495 @@
+/*
+* Copyright 2023-2024 Juan Miguel Giraldo
+*
+* Licensed under the Apache License, Version 2.0 (the "License");
+* you may not use this file except in compliance with the License.
+* You may obtain a copy of the License at
+*
+*     http://www.apache.org/licenses/LICENSE-2.0
+*
+* Unless required by applicable law or agreed to in writing, software
+* distributed under the License is distributed on an "AS IS" BASIS,
+* WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+* See the License for the specific language governing permissions and
+* limitations under the License.
+*/
+
+//! virtio-blk transport: discovers the device over legacy PCI, negotiates
+//! the split virtqueue, and exposes [`read_block`]/[`write_block`] in terms
+//! of 512-byte sectors so there's a real disk to read from and write to
+//! instead of the in-memory state `fs::init()` starts with.
+//!
+//! `block_cache` isn't part of this checkout, so nothing yet sits on top of
+//! [`read_block`]/[`write_block`] to actually cache and flush VKFS blocks -
+//! `gpt` is the only current caller, and only of [`read_block_absolute`],
+//! to read the protective MBR and GPT headers at fixed LBAs. This module on
+//! its own only gets as far as negotiate-and-probe; wiring it into the
+//! filesystem's block cache is follow-up work.
+//!
+//! Every address this driver hands to the device for DMA - the virtqueue
+//! itself and the per-transfer header/data/status buffers - is translated
+//! from a kernel virtual address to a physical one via [`translate`]
+//! first. VEKOS is not identity-mapped (`kernel_main` passes
+//! `boot_info.physical_memory_offset` into `MemoryManager::new`), so a raw
+//! `as_ptr() as u64` would hand the device the wrong physical page.
+//! [`set_phys_mem_offset`] must be called once paging is up and before
+//! [`init`].
+
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{fence, Ordering};
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::page_table::FrameError;
+use x86_64::structures::paging::{PageTable, PageTableFlags};
+use x86_64::VirtAddr;
+
+pub const SECTOR_SIZE: usize = 512;
+const QUEUE_SIZE: usize = 256;
+
+const VIRTIO_PCI_VENDOR_ID: u16 = 0x1af4;
+const VIRTIO_BLK_DEVICE_ID_LEGACY: u16 = 0x1001;
+
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+
+#[derive(Debug)]
+pub enum VirtioBlkError {
+    DeviceNotFound,
+    QueueSetupFailed,
+    IoError,
+    AddressTranslationFailed,
+}
+
+/// Offset of the direct physical memory map, the same value `kernel_main`
+/// passes to `MemoryManager::new`. Every buffer this driver hands to the
+/// device - the queue/rings and the per-transfer header/data/status - is a
+/// kernel virtual address and has to be translated through the active page
+/// tables before it means anything to the device doing DMA.
+static PHYS_MEM_OFFSET: Mutex<Option<u64>> = Mutex::new(None);
+
+/// Records the physical memory offset so [`init`] can translate the
+/// virtqueue and transfer buffers it hands to the device. Must be called
+/// before `init()`, once paging is up.
+pub fn set_phys_mem_offset(offset: u64) {
+    *PHYS_MEM_OFFSET.lock() = Some(offset);
+}
+
+/// Walks the active four-level page table to translate a kernel virtual
+/// address into the physical address the device needs for DMA. Mirrors
+/// the direct-physical-map scheme `MemoryManager` already assumes: once a
+/// frame is found, the rest of the translation is just an offset within
+/// that frame.
+fn translate(va: u64) -> Result<u64, VirtioBlkError> {
+    let phys_mem_offset = PHYS_MEM_OFFSET
+        .lock()
+        .ok_or(VirtioBlkError::AddressTranslationFailed)?;
+    let va = VirtAddr::new(va);
+
+    let (level_4_frame, _) = Cr3::read();
+    let table_indexes = [
+        va.p4_index(),
+        va.p3_index(),
+        va.p2_index(),
+        va.p1_index(),
+    ];
+    let mut frame = level_4_frame;
+
+    for (depth, &index) in table_indexes.iter().enumerate() {
+        let virt = phys_mem_offset + frame.start_address().as_u64();
+        let table_ptr: *const PageTable = virt as *const PageTable;
+        let table = unsafe { &*table_ptr };
+        let entry = &table[index];
+
+        if depth == 3 {
+            if !entry.flags().contains(PageTableFlags::PRESENT) {
+                return Err(VirtioBlkError::AddressTranslationFailed);
+            }
+            return Ok(entry.addr().as_u64() + u64::from(va.page_offset()));
+        }
+
+        frame = match entry.frame() {
+            Ok(frame) => frame,
+            Err(FrameError::FrameNotPresent) => return Err(VirtioBlkError::AddressTranslationFailed),
+            Err(FrameError::HugeFrame) => {
+                // A huge entry at the P3 level maps 1GiB, at the P2 level 2MiB.
+                let frame_mask = if depth == 1 { 0x3fff_ffff } else { 0x1f_ffff };
+                return Ok(entry.addr().as_u64() + (va.as_u64() & frame_mask));
+            }
+        };
+    }
+
+    Err(VirtioBlkError::AddressTranslationFailed)
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct VirtqDesc {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+#[repr(C)]
+struct VirtqAvail {
+    flags: u16,
+    idx: u16,
+    ring: [u16; QUEUE_SIZE],
+}
+
+#[repr(C)]
+struct VirtqUsedElem {
+    id: u32,
+    len: u32,
+}
+
+#[repr(C)]
+struct VirtqUsed {
+    flags: u16,
+    idx: u16,
+    ring: [VirtqUsedElem; QUEUE_SIZE],
+}
+
+#[repr(C)]
+struct BlkReqHeader {
+    req_type: u32,
+    reserved: u32,
+    sector: u64,
+}
+
+/// Legacy virtio PCI I/O port layout (virtio spec 1.0, section 4.1.4).
+struct VirtioPciIo {
+    io_base: u16,
+}
+
+impl VirtioPciIo {
+    const DEVICE_FEATURES: u16 = 0x00;
+    const DRIVER_FEATURES: u16 = 0x04;
+    const QUEUE_ADDRESS: u16 = 0x08;
+    const QUEUE_SIZE: u16 = 0x0c;
+    const QUEUE_SELECT: u16 = 0x0e;
+    const QUEUE_NOTIFY: u16 = 0x10;
+    const DEVICE_STATUS: u16 = 0x12;
+    const DEVICE_CONFIG: u16 = 0x14;
+
+    unsafe fn write8(&self, offset: u16, value: u8) {
+        Port::<u8>::new(self.io_base + offset).write(value);
+    }
+
+    unsafe fn write16(&self, offset: u16, value: u16) {
+        Port::<u16>::new(self.io_base + offset).write(value);
+    }
+
+    unsafe fn write32(&self, offset: u16, value: u32) {
+        Port::<u32>::new(self.io_base + offset).write(value);
+    }
+
+    unsafe fn read16(&self, offset: u16) -> u16 {
+        Port::<u16>::new(self.io_base + offset).read()
+    }
+
+    unsafe fn read32(&self, offset: u16) -> u32 {
+        Port::<u32>::new(self.io_base + offset).read()
+    }
+
+    unsafe fn capacity_sectors(&self) -> u64 {
+        let lo = self.read32(Self::DEVICE_CONFIG) as u64;
+        let hi = self.read32(Self::DEVICE_CONFIG + 4) as u64;
+        (hi << 32) | lo
+    }
+}
+
+/// The descriptor table, available ring, and used ring as one physically
+/// contiguous allocation (the legacy virtio queue layout the single
+/// `QUEUE_ADDRESS` page-frame-number register assumes), tracked by both
+/// its kernel virtual address (for the driver's own reads/writes) and its
+/// physical address (for the `addr` field of each descriptor and the
+/// frame number handed to the device).
+struct VirtqueueLayout {
+    base_va: u64,
+    base_pa: u64,
+    avail_offset: usize,
+    used_offset: usize,
+    avail: Mutex<AvailState>,
+    used_last_idx: Mutex<u16>,
+}
+
+impl VirtqueueLayout {
+    fn desc_ptr(&self, index: usize) -> *mut VirtqDesc {
+        (self.base_va as *mut VirtqDesc).wrapping_add(index)
+    }
+
+    fn avail_va(&self) -> u64 {
+        self.base_va + self.avail_offset as u64
+    }
+
+    fn used_va(&self) -> u64 {
+        self.base_va + self.used_offset as u64
+    }
+}
+
+struct AvailState {
+    idx: u16,
+}
+
+struct VirtioBlkDevice {
+    io: VirtioPciIo,
+    queue: VirtqueueLayout,
+    capacity_sectors: u64,
+}
+
+static DEVICE: Mutex<Option<VirtioBlkDevice>> = Mutex::new(None);
+
+/// Scans bus/device/function space on the legacy PCI configuration ports
+/// (0xCF8/0xCFC) for a virtio-blk function and returns its assigned BAR0
+/// I/O port base.
+fn find_virtio_blk_io_base() -> Option<u16> {
+    const CONFIG_ADDRESS: u16 = 0xCF8;
+    const CONFIG_DATA: u16 = 0xCFC;
+
+    unsafe fn pci_read32(bus: u8, device: u8, function: u8, offset: u8) -> u32 {
+        let address: u32 = (1 << 31)
+            | ((bus as u32) << 16)
+            | ((device as u32) << 11)
+            | ((function as u32) << 8)
+            | ((offset as u32) & 0xfc);
+        Port::<u32>::new(CONFIG_ADDRESS).write(address);
+        Port::<u32>::new(CONFIG_DATA).read()
+    }
+
+    for bus in 0..=255u16 {
+        for device in 0..32u8 {
+            for function in 0..8u8 {
+                let id = unsafe { pci_read32(bus as u8, device, function, 0x00) };
+                if id == 0xffff_ffff {
+                    continue;
+                }
+                let vendor = (id & 0xffff) as u16;
+                let device_id = (id >> 16) as u16;
+                if vendor == VIRTIO_PCI_VENDOR_ID && device_id == VIRTIO_BLK_DEVICE_ID_LEGACY {
+                    let bar0 = unsafe { pci_read32(bus as u8, device, function, 0x10) };
+                    if bar0 & 0x1 == 1 {
+                        return Some((bar0 & 0xffff_fffc) as u16);
+                    }
+                }
+            }
+        }
+    }
+    None
+}
+
+const PAGE_SIZE: usize = 4096;
+
+fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+/// Lays out the descriptor table, available ring, and used ring as one
+/// contiguous, page-aligned allocation per the legacy virtio queue layout,
+/// then translates its base address through the page tables so the
+/// physical frame number can be handed to `QUEUE_ADDRESS`.
+fn build_queue() -> Result<VirtqueueLayout, VirtioBlkError> {
+    let desc_table_size = QUEUE_SIZE * core::mem::size_of::<VirtqDesc>();
+    let avail_offset = desc_table_size;
+    let avail_size = core::mem::size_of::<VirtqAvail>();
+    let used_offset = align_up(avail_offset + avail_size, PAGE_SIZE);
+    let used_size = core::mem::size_of::<VirtqUsed>();
+    let total_size = used_offset + used_size;
+
+    let mut backing = vec![0u8; total_size + PAGE_SIZE];
+    let base_va = align_up(backing.as_mut_ptr() as usize, PAGE_SIZE) as u64;
+    core::mem::forget(backing);
+
+    let base_pa = translate(base_va)?;
+
+    for i in 0..QUEUE_SIZE {
+        unsafe {
+            core::ptr::write_volatile(
+                (base_va as *mut VirtqDesc).add(i),
+                VirtqDesc { addr: 0, len: 0, flags: 0, next: 0 },
+            );
+        }
+    }
+
+    Ok(VirtqueueLayout {
+        base_va,
+        base_pa,
+        avail_offset,
+        used_offset,
+        avail: Mutex::new(AvailState { idx: 0 }),
+        used_last_idx: Mutex::new(0),
+    })
+}
+
+/// Negotiates the virtio-blk device over legacy PCI and brings up queue 0.
+/// Registered during boot right before `fs::init()`.
+pub fn init() -> Result<(), VirtioBlkError> {
+    let io_base = find_virtio_blk_io_base().ok_or(VirtioBlkError::DeviceNotFound)?;
+    let io = VirtioPciIo { io_base };
+
+    unsafe {
+        io.write8(VirtioPciIo::DEVICE_STATUS, 0);
+        io.write8(VirtioPciIo::DEVICE_STATUS, 1);
+        io.write8(VirtioPciIo::DEVICE_STATUS, 1 | 2);
+
+        let _features = io.read16(VirtioPciIo::DEVICE_FEATURES);
+        io.write32(VirtioPciIo::DRIVER_FEATURES, 0);
+
+        io.write16(VirtioPciIo::QUEUE_SELECT, 0);
+        let negotiated_size = io.read16(VirtioPciIo::QUEUE_SIZE);
+        if negotiated_size == 0 {
+            return Err(VirtioBlkError::QueueSetupFailed);
+        }
+
+        let queue = build_queue()?;
+        io.write32(VirtioPciIo::QUEUE_ADDRESS, (queue.base_pa >> 12) as u32);
+
+        io.write8(VirtioPciIo::DEVICE_STATUS, 1 | 2 | 4);
+        let capacity_sectors = io.capacity_sectors();
+
+        *DEVICE.lock() = Some(VirtioBlkDevice { io, queue, capacity_sectors });
+    }
+
+    Ok(())
+}
+
+/// Total sectors reported by the device config, or `None` if no device
+/// was negotiated.
+pub fn capacity_sectors() -> Option<u64> {
+    DEVICE.lock().as_ref().map(|d| d.capacity_sectors)
+}
+
+/// Submits a 3-descriptor chain. `header_pa`/`data_pa`/`status_pa` must
+/// already be physical addresses - the device has no notion of the
+/// kernel's page tables, so a virtual address here would DMA into
+/// whatever physical page happens to share that address, not the buffer
+/// the caller intended.
+fn submit_chain(device: &mut VirtioBlkDevice, header_pa: u64, data_pa: u64, data_len: u32,
+    status_pa: u64, write: bool) {
+    unsafe {
+        core::ptr::write_volatile(
+            device.queue.desc_ptr(0),
+            VirtqDesc {
+                addr: header_pa,
+                len: core::mem::size_of::<BlkReqHeader>() as u32,
+                flags: VIRTQ_DESC_F_NEXT,
+                next: 1,
+            },
+        );
+        core::ptr::write_volatile(
+            device.queue.desc_ptr(1),
+            VirtqDesc {
+                addr: data_pa,
+                len: data_len,
+                flags: VIRTQ_DESC_F_NEXT | if write { 0 } else { VIRTQ_DESC_F_WRITE },
+                next: 2,
+            },
+        );
+        core::ptr::write_volatile(
+            device.queue.desc_ptr(2),
+            VirtqDesc {
+                addr: status_pa,
+                len: 1,
+                flags: VIRTQ_DESC_F_WRITE,
+                next: 0,
+            },
+        );
+    }
+
+    let mut avail = device.queue.avail.lock();
+    let slot = (avail.idx as usize) % QUEUE_SIZE;
+    let avail_ring_ptr = (device.queue.avail_va() + 4 + slot as u64 * 2) as *mut u16;
+    unsafe { core::ptr::write_volatile(avail_ring_ptr, 0) };
+    avail.idx = avail.idx.wrapping_add(1);
+    let avail_idx_ptr = (device.queue.avail_va() + 2) as *mut u16;
+    unsafe { core::ptr::write_volatile(avail_idx_ptr, avail.idx) };
+    drop(avail);
+
+    fence(Ordering::SeqCst);
+
+    unsafe { device.io.write16(VirtioPciIo::QUEUE_NOTIFY, 0) };
+
+    let used_idx_ptr = (device.queue.used_va() + 2) as *const u16;
+    loop {
+        let current = unsafe { core::ptr::read_volatile(used_idx_ptr) };
+        let mut last = device.queue.used_last_idx.lock();
+        if current != *last {
+            *last = current;
+            break;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// Starting LBA of the VKFS partition `gpt::find_vkfs_partition` located,
+/// applied by [`read_block`]/[`write_block`] so the filesystem layer can
+/// keep addressing sectors relative to its own volume instead of the raw
+/// disk. Defaults to 0 (volume starts at block 0) until a partition is
+/// found, preserving the pre-GPT behavior.
+static PARTITION_OFFSET_SECTORS: Mutex<u64> = Mutex::new(0);
+
+/// Records the VKFS partition's starting LBA. Called once after
+/// `gpt::read_partitions`/`gpt::find_vkfs_partition` succeed, before
+/// `fs::init()` issues any block IO.
+pub fn set_partition_offset(start_lba: u64) {
+    *PARTITION_OFFSET_SECTORS.lock() = start_lba;
+}
+
+fn do_transfer(sector: u64, buf: &mut [u8], write: bool) -> Result<(), VirtioBlkError> {
+    if buf.len() != SECTOR_SIZE {
+        return Err(VirtioBlkError::IoError);
+    }
+
+    let mut device_lock = DEVICE.lock();
+    let device = device_lock.as_mut().ok_or(VirtioBlkError::DeviceNotFound)?;
+
+    let header = BlkReqHeader {
+        req_type: if write { VIRTIO_BLK_T_OUT } else { VIRTIO_BLK_T_IN },
+        reserved: 0,
+        sector,
+    };
+    let header_pa = translate(&header as *const BlkReqHeader as u64)?;
+    let data_pa = translate(buf.as_mut_ptr() as u64)?;
+    let mut status: u8 = 0xff;
+    let status_pa = translate(&mut status as *mut u8 as u64)?;
+
+    submit_chain(device, header_pa, data_pa, SECTOR_SIZE as u32, status_pa, write);
+
+    if status == 0 {
+        Ok(())
+    } else {
+        Err(VirtioBlkError::IoError)
+    }
+}
+
+/// Reads one 512-byte sector at a raw disk LBA, bypassing the VKFS
+/// partition offset. Used by `gpt` to read the protective MBR and GPT
+/// headers, which live at fixed absolute LBAs regardless of where any
+/// partition starts.
+pub(crate) fn read_block_absolute(sector: u64, buf: &mut [u8]) -> Result<(), VirtioBlkError> {
+    do_transfer(sector, buf, false)
+}
+
+/// Reads one 512-byte sector into `buf`, relative to the VKFS partition's
+/// starting LBA (block 0 if no GPT partition was found).
+pub fn read_block(sector: u64, buf: &mut [u8]) -> Result<(), VirtioBlkError> {
+    let offset = *PARTITION_OFFSET_SECTORS.lock();
+    do_transfer(offset + sector, buf, false)
+}
+
+/// Writes one 512-byte sector from `buf`, relative to the VKFS partition's
+/// starting LBA (block 0 if no GPT partition was found).
+pub fn write_block(sector: u64, buf: &mut [u8]) -> Result<(), VirtioBlkError> {
+    let offset = *PARTITION_OFFSET_SECTORS.lock();
+    do_transfer(offset + sector, buf, true)
+}
+
+/// Whether `init()` found and negotiated a device.
+pub fn is_available() -> bool {
+    DEVICE.lock().is_some()
+}